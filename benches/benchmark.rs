@@ -12,7 +12,9 @@ extern crate serde_derive;
 extern crate serde_json;
 
 use handlebars::{to_json, Handlebars};
-use zapper::compile;
+use std::fs::{File, OpenOptions};
+use zapper::tokenizer::Tokenizer;
+use zapper::{ast, compile, optimizer, Bytecode};
 
 #[derive(Clone, ZapperRunner, Serialize)]
 #[filter = "sqrt/0n"]
@@ -49,21 +51,20 @@ fn toupper(_data: &Person, _args: &[f64], input: &str, buffer: &mut String) {
     }
 }
 
-fn bench_zapper(c: &mut Criterion) {
-    let template = "{{provider}} {{provider_code}} {{id}} {{name}} {{age}} {{weight}}kg\n";
-    let env = Provider {
+const TEMPLATE: &str = "{{provider}} {{provider_code}} {{id}} {{name}} {{age}} {{weight}}kg\n";
+
+// a template made up almost entirely of numbers, to isolate the cost of formatting them
+const NUMERIC_TEMPLATE: &str = "{{id}} {{age}} {{weight}} {{weight / 2.2}} {{age * 1.5 + id}} {{weight | round 2}} {{weight | toupper}}\n";
+
+fn provider() -> Provider {
+    Provider {
         provider: "apns".to_string(),
         provider_code: 31,
-    };
-    let mut bytecode = match compile(template, &env) {
-        Ok(bc) => bc,
-        Err(err) => {
-            eprintln!("error compiling template: {}", err);
-            return;
-        }
-    };
-
-    // build up a group of 1000 (similar) people
+    }
+}
+
+// build up a group of 1000 (similar) people
+fn group() -> Vec<Person> {
     let mut group = vec![];
     for i in 0..1000 {
         group.push(Person {
@@ -73,7 +74,18 @@ fn bench_zapper(c: &mut Criterion) {
             weight: 170.3 + i as f64,
         });
     }
+    group
+}
 
+// compiles `template` against the provider, along with a group of people to render it for
+fn setup(template: &str) -> (Bytecode<PersonNums, PersonStrs, PersonFilters>, Vec<Person>) {
+    let bytecode = compile(template, &provider())
+        .unwrap_or_else(|err| panic!("error compiling template: {}", err));
+    (bytecode, group())
+}
+
+fn bench_zapper(c: &mut Criterion) {
+    let (mut bytecode, group) = setup(TEMPLATE);
     c.bench_function("zapper", move |b| {
         b.iter(|| {
             let mut output = Vec::new();
@@ -86,37 +98,108 @@ fn bench_zapper(c: &mut Criterion) {
 }
 
 fn bench_zapper_par(c: &mut Criterion) {
-    let template = "{{provider}} {{provider_code}} {{id}} {{name}} {{age}} {{weight}}kg\n";
-    let env = Provider {
-        provider: "apns".to_string(),
-        provider_code: 31,
-    };
-    let bytecode = match compile(template, &env) {
-        Ok(bc) => bc,
-        Err(err) => {
-            eprintln!("error compiling template: {}", err);
-            return;
-        }
-    };
-
-    // build up a group of 1000 (similar) people
-    let mut group = vec![];
-    for i in 0..1000 {
-        group.push(Person {
-            id: 12 + i,
-            name: "Bob".to_string(),
-            age: 49,
-            weight: 170.3 + i as f64,
+    let (bytecode, group) = setup(TEMPLATE);
+    c.bench_function("zapper_par", move |b| {
+        b.iter(|| {
+            let mut output = Vec::new();
+            bytecode.par_render(&group, &mut output, 50).unwrap();
+            output
+        })
+    });
+}
+
+fn bench_zapper_numeric(c: &mut Criterion) {
+    let (mut bytecode, group) = setup(NUMERIC_TEMPLATE);
+    c.bench_function("zapper_numeric", move |b| {
+        b.iter(|| {
+            let mut output = Vec::new();
+            for person in &group {
+                bytecode.render(person, &mut output).unwrap();
+            }
+            output
+        })
+    });
+}
+
+// the closure backend, on the same templates as the "zapper" and "zapper_numeric" benchmarks
+fn bench_zapper_closures(c: &mut Criterion) {
+    let templates = [
+        ("zapper_closures", TEMPLATE),
+        ("zapper_numeric_closures", NUMERIC_TEMPLATE),
+    ];
+    for &(name, template) in &templates {
+        let (bytecode, group) = setup(template);
+        c.bench_function(name, move |b| {
+            let mut program = bytecode.to_closures();
+            b.iter(|| {
+                let mut output = Vec::new();
+                for person in &group {
+                    program.render(person, &mut output).unwrap();
+                }
+                output
+            })
         });
     }
+}
 
-    c.bench_function("zapper_par", move |b| {
+// renders an arithmetic-heavy template both with and without the peephole pass
+fn bench_zapper_peephole(c: &mut Criterion) {
+    let template = "{{-age}} {{age * 1.5 + 1}} {{weight / 2.2 - 10}} {{id * 2 + 1}} {{-weight * 3 | round 1}}\n";
+    let (mut fused, group) = setup(template);
+    let env = provider();
+    let ast = optimizer::optimize(ast::parse(Tokenizer::new(template)).unwrap(), &env);
+    let mut naive = Bytecode::from_ast(ast, &env).unwrap();
+    let group2 = group.clone();
+
+    c.bench_function("zapper_peephole", move |b| {
         b.iter(|| {
             let mut output = Vec::new();
-            bytecode.par_render(&group, &mut output, 50).unwrap();
+            for person in &group {
+                fused.render(person, &mut output).unwrap();
+            }
             output
         })
     });
+
+    c.bench_function("zapper_no_peephole", move |b| {
+        b.iter(|| {
+            let mut output = Vec::new();
+            for person in &group2 {
+                naive.render(person, &mut output).unwrap();
+            }
+            output
+        })
+    });
+}
+
+// writes straight to the null device without any buffering, so the cost of each write call shows up in the results
+fn null_device() -> File {
+    let path = if cfg!(windows) { "nul" } else { "/dev/null" };
+    OpenOptions::new().write(true).open(path).unwrap()
+}
+
+fn bench_zapper_unbuffered(c: &mut Criterion) {
+    let (mut bytecode, group) = setup(TEMPLATE);
+    let mut output = null_device();
+    c.bench_function("zapper_unbuffered", move |b| {
+        b.iter(|| {
+            for person in &group {
+                bytecode.render(person, &mut output).unwrap();
+            }
+        })
+    });
+}
+
+fn bench_zapper_vectored(c: &mut Criterion) {
+    let (mut bytecode, group) = setup(TEMPLATE);
+    let mut output = null_device();
+    c.bench_function("zapper_vectored", move |b| {
+        b.iter(|| {
+            for person in &group {
+                bytecode.render_vectored(person, &mut output).unwrap();
+            }
+        })
+    });
 }
 
 fn bench_hbs(c: &mut Criterion) {
@@ -128,15 +211,7 @@ fn bench_hbs(c: &mut Criterion) {
         .register_template_string("table", template)
         .unwrap();
 
-    let mut group = vec![];
-    for i in 0..1000 {
-        group.push(Person {
-            id: 12 + i,
-            name: "Bob".to_string(),
-            age: 49,
-            weight: 170.3 + i as f64,
-        });
-    }
+    let group = group();
 
     let mut data = Map::new();
     data.insert("provider".to_string(), to_json(&"apns".to_string()));
@@ -158,6 +233,11 @@ pub fn benches() {
         .measurement_time(Duration::from_secs(40));
     bench_zapper(&mut criterion);
     bench_zapper_par(&mut criterion);
+    bench_zapper_numeric(&mut criterion);
+    bench_zapper_peephole(&mut criterion);
+    bench_zapper_closures(&mut criterion);
+    bench_zapper_unbuffered(&mut criterion);
+    bench_zapper_vectored(&mut criterion);
     bench_hbs(&mut criterion);
 }
 
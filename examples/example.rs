@@ -8,7 +8,8 @@ use std::io::stdout;
 #[derive(ZapperRunner)]
 #[filter = "sqrt/0n"]
 #[filter = "round/1n"]
-#[filter = "toupper/0s"]
+#[filter = "toupper/0s/pure"]
+#[zapper(render = "render_summary", env = "Provider", source = "{{provider | toupper}} {{provider_code + 4}} {{id}} {{name | toupper}} {{age | sqrt}} {{weight / 2.2 | round 2}}kg\n")]
 struct Person {
     id: u64,
     name: String,
@@ -37,14 +38,15 @@ fn round(_data: &Person, args: &[f64], input: f64) -> f64 {
     value / factor
 }
 
-fn toupper(_data: &Person, _args: &[f64], input: &str, buffer: &mut String) {
+// pure filters don't get the runner, which lets zapper run them on constants while compiling
+fn toupper(_args: &[f64], input: &str, buffer: &mut String) {
     for c in input.as_bytes() {
         buffer.push(c.to_ascii_uppercase() as char)
     }
 }
 
 fn main() {
-    let template = "{{provider}} {{provider_code + 4}} {{id}} {{name | toupper}} {{age | sqrt}} {{weight / 2.2 | round 2}}kg\n";
+    let template = "{{provider | toupper}} {{provider_code + 4}} {{id}} {{name | toupper}} {{age | sqrt}} {{weight / 2.2 | round 2}}kg\n";
 
     let env = Provider {
         provider: "john doe".to_string(),
@@ -75,7 +77,12 @@ fn main() {
     let stdout = stdout();
     let mut stdout_lock = stdout.lock();
 
-    for person in group {
-        bytecode.render(&person, &mut stdout_lock).unwrap();
+    for person in &group {
+        bytecode.render(person, &mut stdout_lock).unwrap();
+    }
+
+    // the same template, compiled to Rust along with the runner
+    for person in &group {
+        person.render_summary(&env, &mut stdout_lock).unwrap();
     }
 }
@@ -68,14 +68,31 @@ impl<'a> Environment<'a, PersonNums, PersonStrs, PersonFilters> for Provider {
         }
     }
 
-    fn filter(name: &str) -> Option<(PersonFilters, usize, FilterInput<PersonStrs>)> {
+    fn filter(name: &str) -> Option<(PersonFilters, usize, FilterInput<PersonStrs>, bool)> {
         match name {
-            "sqrt" => Some((PersonFilters::Sqrt, 0, FilterInput::Numeric)),
-            "round" => Some((PersonFilters::Round, 1, FilterInput::Numeric)),
-            "toupper" => Some((PersonFilters::ToUpper, 0, FilterInput::Stringified)),
+            "sqrt" => Some((PersonFilters::Sqrt, 0, FilterInput::Numeric, false)),
+            "round" => Some((PersonFilters::Round, 1, FilterInput::Numeric, false)),
+            // toupper only looks at its input, so it can run at compile time on constants like provider
+            "toupper" => Some((PersonFilters::ToUpper, 0, FilterInput::Stringified, true)),
             _ => None,
         }
     }
+
+    fn pure_filter_str(
+        &self,
+        filter: PersonFilters,
+        _args: &[f64],
+        input: &str,
+        buffer: &mut String,
+    ) -> bool {
+        match filter {
+            PersonFilters::ToUpper => {
+                toupper(input, buffer);
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
 impl Runner<PersonNums, PersonStrs, PersonFilters> for Person {
@@ -128,18 +145,20 @@ impl Runner<PersonNums, PersonStrs, PersonFilters> for Person {
         buffer: &mut String,
     ) {
         match filter {
-            PersonFilters::ToUpper => {
-                for c in input.as_bytes() {
-                    buffer.push(c.to_ascii_uppercase() as char)
-                }
-            }
+            PersonFilters::ToUpper => toupper(&input, buffer),
             _ => unreachable!(),
         }
     }
 }
 
+fn toupper(input: &str, buffer: &mut String) {
+    for c in input.as_bytes() {
+        buffer.push(c.to_ascii_uppercase() as char)
+    }
+}
+
 fn main() {
-    let template = "{{provider}} {{provider_code + 4}} {{id}} {{name | toupper}} {{age | sqrt}} {{weight / 2.2 | round 2}}kg\n";
+    let template = "{{provider | toupper}} {{provider_code + 4}} {{id}} {{name | toupper}} {{age | sqrt}} {{weight / 2.2 | round 2}}kg\n";
 
     let env = Provider {
         provider: "john doe".to_string(),
@@ -2,18 +2,38 @@
 
 use super::{Environment, FilterInput, Runner};
 use ast::*;
+use number::NumBuffer;
 use std::borrow::Cow;
-use std::cell::RefCell;
-use std::fmt::Debug;
+use std::cell::{Cell, RefCell};
+use std::fmt::{self, Debug};
 use std::io::Write;
+use std::mem;
 use std::ops::DerefMut;
 use tokenizer::Operator;
+use vectored::VectoredBatch;
 
+mod closures;
+mod disassemble;
+mod memoize;
+mod peephole;
+mod serialize;
+mod specialize;
+
+pub use self::closures::ClosureProgram;
+pub use self::disassemble::DisassembledInstr;
+pub use self::serialize::FORMAT_VERSION;
+pub(crate) use self::serialize::{folded_constants, folded_filters};
+use self::serialize::{Constant, FoldedFilter};
+
+#[cfg(feature = "async")]
+use futures_io::AsyncWrite;
 #[cfg(feature = "rayon")]
 use rayon::prelude::*;
+#[cfg(feature = "async")]
+use render_async::RenderAsync;
 
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 enum Instr<NumEnum, StrEnum, FilterEnum> {
     PrintRaw(usize, usize), //prints a range from the resource string
     PrintStr(StrEnum),
@@ -30,6 +50,75 @@ enum Instr<NumEnum, StrEnum, FilterEnum> {
     Sub,
     Mul,
     Div,
+
+    // superinstructions produced by the peephole pass
+    AddImm(f64),
+    SubImm(f64),
+    MulImm(f64),
+    DivImm(f64),
+    Neg,
+
+    // produced by the memoisation pass, so each value is fetched from the runner once per render. The usize is the
+    // slot the value is saved in; numbers and strings are saved in separate sets of slots.
+    SaveNum(NumEnum, usize),
+    LoadNum(usize),
+    SaveCall(FilterEnum, Vec<f64>, usize),
+    PrintSavedNum(usize),
+    SaveStr(StrEnum, usize),
+    SaveOutput(usize),
+    PrintSaved(usize),
+}
+
+impl<NumEnum, StrEnum, FilterEnum> Instr<NumEnum, StrEnum, FilterEnum> {
+    /// The number of values this instruction pops off the stack, and the number it pushes back on.
+    fn stack_effect(&self) -> (usize, usize) {
+        match *self {
+            Instr::PrintRaw(..)
+            | Instr::PrintStr(_)
+            | Instr::PrintNum(_)
+            | Instr::CallId(..)
+            | Instr::CallStr(..)
+            | Instr::SaveStr(..)
+            | Instr::SaveOutput(_)
+            | Instr::PrintSaved(_)
+            | Instr::PrintSavedNum(_) => (0, 0),
+            Instr::PushImm(_) | Instr::PushNum(_) | Instr::SaveNum(..) | Instr::LoadNum(_) => {
+                (0, 1)
+            }
+            Instr::PrintReg | Instr::CallReg(..) | Instr::CallRegStr(..) | Instr::SaveCall(..) => {
+                (1, 0)
+            }
+            Instr::Add | Instr::Sub | Instr::Mul | Instr::Div => (2, 1),
+            Instr::AddImm(_)
+            | Instr::SubImm(_)
+            | Instr::MulImm(_)
+            | Instr::DivImm(_)
+            | Instr::Neg => (1, 1),
+        }
+    }
+}
+
+impl<NumEnum, StrEnum, FilterEnum> Bytecode<NumEnum, StrEnum, FilterEnum> {
+    /// Walks the stack effects of every instruction, returning the deepest the stack ever gets. This fails if an
+    /// instruction would pop a value which isn't there, or if values are left over at the end. The render loop
+    /// relies on this check instead of checking every push and pop.
+    fn stack_depth(&self) -> Result<usize, String> {
+        let mut depth = 0usize;
+        let mut max_depth = 0;
+        for instr in &self.instructions {
+            let (pops, pushes) = instr.stack_effect();
+            depth = depth
+                .checked_sub(pops)
+                .ok_or_else(|| "Bytecode underflows the stack".to_string())?
+                + pushes;
+            max_depth = max_depth.max(depth);
+        }
+
+        if depth != 0 {
+            return Err("Bytecode leaves values on the stack".to_string());
+        }
+        Ok(max_depth)
+    }
 }
 
 #[allow(unused)]
@@ -37,14 +126,226 @@ enum Instr<NumEnum, StrEnum, FilterEnum> {
 pub struct Bytecode<NumEnum, StrEnum, FilterEnum> {
     buffer: Option<String>,
     stack: Option<Vec<f64>>,
+    scratch: Option<Vec<u8>>,
     raw_text: String,
     instructions: Vec<Instr<NumEnum, StrEnum, FilterEnum>>,
+    info: Vec<InstrInfo>,
+    // the most values the stack ever holds, as proven by `stack_depth`
+    max_stack: usize,
+    // the number of slots the memoisation instructions save numbers and strings in, as checked by `saved_slots`
+    saved_nums: usize,
+    saved_texts: usize,
+    // the environment constants that were folded in while compiling, which `from_bytes` checks the environment
+    // still agrees with
+    constants: Vec<(String, Constant)>,
+    // likewise the pure filter calls whose output was folded in, which `from_bytes` runs again
+    folded_filters: Vec<FoldedFilter>,
+}
+
+/// The source-level names an instruction refers to, and the part of the template it was compiled from. These are
+/// kept alongside the instructions rather than inside them so that the render loop doesn't pay for them.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct InstrInfo {
+    var: Option<String>,
+    filter: Option<String>,
+    span: Span,
+}
+
+/// A sink for rendered text, which lets a single VM loop drive both the `io::Write` and `fmt::Write` render paths.
+trait Output {
+    type Error;
+
+    fn write_str(&mut self, string: &str) -> Result<(), Self::Error>;
+    fn write_num(&mut self, num: f64) -> Result<(), Self::Error>;
 }
 
-macro_rules! pop {
-    ($stack:ident) => {
-        $stack.pop().unwrap_or_else(|| panic!("stack underflow!"))
-    };
+struct IoOutput<'w, W: 'w + Write + ?Sized>(&'w mut W);
+
+impl<'w, W: 'w + Write + ?Sized> Output for IoOutput<'w, W> {
+    type Error = ::std::io::Error;
+
+    fn write_str(&mut self, string: &str) -> Result<(), Self::Error> {
+        self.0.write_all(string.as_bytes())
+    }
+
+    fn write_num(&mut self, num: f64) -> Result<(), Self::Error> {
+        self.0.write_all(NumBuffer::new().format(num).as_bytes())
+    }
+}
+
+struct FmtOutput<'w, W: 'w + fmt::Write + ?Sized>(&'w mut W);
+
+impl<'w, W: 'w + fmt::Write + ?Sized> Output for FmtOutput<'w, W> {
+    type Error = fmt::Error;
+
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        self.0.write_str(string)
+    }
+
+    fn write_num(&mut self, num: f64) -> fmt::Result {
+        self.0.write_str(NumBuffer::new().format(num))
+    }
+}
+
+/// The output of a single instruction.
+enum Piece<'r> {
+    Nothing,
+    Str(Cow<'r, str>),
+    Num(f64),
+    /// the output was written into the reusable string buffer
+    Buffer,
+    /// a string saved earlier in the render, at this range of the saved text
+    Saved(usize, usize),
+}
+
+thread_local!(static SAVED_TEXT: Cell<SavedText> = Cell::new(SavedText::default()));
+
+/// The strings saved by memoised bytecode, kept apart from the buffer filters write into so that a filter is always
+/// handed an empty buffer. Each thread reuses the same storage from one render to the next, which it gets back when
+/// the render is dropped.
+#[derive(Default)]
+struct SavedText {
+    text: String,
+    ranges: Vec<(usize, usize)>,
+}
+
+impl SavedText {
+    // takes this thread's storage, emptied and with room for `slots` saved strings. A render nested inside another
+    // one, such as from a filter, finds the storage already taken and gets its own.
+    fn take(slots: usize) -> SavedText {
+        if slots == 0 {
+            return SavedText::default();
+        }
+        let mut saved = SAVED_TEXT.with(Cell::take);
+        saved.text.clear();
+        saved.ranges.clear();
+        saved.ranges.resize(slots, (0, 0));
+        saved
+    }
+
+    // saves a copy of `text` in `slot`
+    fn save(&mut self, slot: usize, text: &str) -> Piece<'static> {
+        let start = self.text.len();
+        self.text.push_str(text);
+        self.ranges[slot] = (start, self.text.len());
+        Piece::Saved(start, self.text.len())
+    }
+}
+
+impl Drop for SavedText {
+    fn drop(&mut self) {
+        if self.ranges.is_empty() {
+            return;
+        }
+        // without any ranges, the storage is dropped as usual if it's replaced, or if the thread is exiting
+        let mut saved = mem::take(self);
+        saved.ranges.clear();
+        let _ = SAVED_TEXT.try_with(|cell| cell.set(saved));
+    }
+}
+
+/// The VM's value stack. It is laid over exactly `max_stack` values of storage, and the stack effects of every
+/// instruction are checked before a Bytecode can be rendered, so pushes and pops never need to check bounds.
+///
+/// The numbers saved by memoised bytecode live in the same storage, just past the end of the stack.
+struct Stack<'s> {
+    values: &'s mut [f64],
+    len: usize,
+    saved: &'s mut [f64],
+    texts: &'s mut SavedText,
+}
+
+impl<'s> Stack<'s> {
+    fn new(
+        storage: &'s mut Vec<f64>,
+        depth: usize,
+        saved: usize,
+        texts: &'s mut SavedText,
+    ) -> Stack<'s> {
+        if storage.len() < depth + saved {
+            storage.resize(depth + saved, 0.0);
+        }
+        let (values, saved) = storage[..depth + saved].split_at_mut(depth);
+        Stack {
+            values,
+            len: 0,
+            saved,
+            texts,
+        }
+    }
+
+    #[inline(always)]
+    fn push(&mut self, val: f64) {
+        debug_assert!(self.len < self.values.len(), "stack overflow!");
+        // the bytecode never holds more than `max_stack` values, which is how many `values` has room for
+        unsafe {
+            *self.values.get_unchecked_mut(self.len) = val;
+        }
+        self.len += 1;
+    }
+
+    #[inline(always)]
+    fn pop(&mut self) -> f64 {
+        debug_assert!(self.len > 0, "stack underflow!");
+        self.len -= 1;
+        // the bytecode never pops a value it didn't push first
+        unsafe { *self.values.get_unchecked(self.len) }
+    }
+}
+
+/// A pull-based renderer returned by `Bytecode::render_cursor`, which yields the rendered output as a series of
+/// chunks. All state lives in the cursor, so rendering can be paused and resumed between calls to `next`.
+pub struct RenderCursor<'r, NumEnum: 'r, StrEnum: 'r, FilterEnum: 'r> {
+    bytecode: &'r Bytecode<NumEnum, StrEnum, FilterEnum>,
+    runner: &'r dyn Runner<NumEnum, StrEnum, FilterEnum>,
+    pc: usize,
+    stack: Vec<f64>,
+    stack_len: usize,
+    texts: SavedText,
+    buffer: String,
+}
+
+impl<'r, NumEnum, StrEnum, FilterEnum> Iterator for RenderCursor<'r, NumEnum, StrEnum, FilterEnum>
+where
+    NumEnum: 'r + Copy + Debug + Send + Sync,
+    StrEnum: 'r + Copy + Debug + Send + Sync + PartialEq,
+    FilterEnum: 'r + Copy + Debug + Send + Sync,
+{
+    type Item = Cow<'r, str>;
+
+    fn next(&mut self) -> Option<Cow<'r, str>> {
+        let bytecode = self.bytecode;
+        while let Some(instr) = bytecode.instructions.get(self.pc) {
+            self.pc += 1;
+            let piece = {
+                let (values, saved) = self.stack.split_at_mut(bytecode.max_stack);
+                let mut stack = Stack {
+                    values,
+                    len: self.stack_len,
+                    saved,
+                    texts: &mut self.texts,
+                };
+                let piece = bytecode.step(instr, self.runner, &mut stack, &mut self.buffer);
+                self.stack_len = stack.len;
+                piece
+            };
+            match piece {
+                Piece::Nothing => {}
+                Piece::Str(string) => return Some(string),
+                Piece::Num(num) => return Some(NumBuffer::new().format(num).to_string().into()),
+                // the buffer can be handed over instead of copied, unless the next instruction saves it
+                Piece::Buffer if bytecode.saved_texts == 0 => {
+                    return Some(mem::take(&mut self.buffer).into())
+                }
+                Piece::Buffer => return Some(self.buffer.clone().into()),
+                Piece::Saved(start, end) => {
+                    return Some(self.texts.text[start..end].to_string().into())
+                }
+            }
+        }
+
+        None
+    }
 }
 
 impl<
@@ -59,17 +360,54 @@ impl<
         ast: Vec<Expr>,
         env: &Env,
     ) -> Result<Bytecode<NumEnum, StrEnum, FilterEnum>, String> {
+        let ast = ast
+            .into_iter()
+            .map(|tree| (tree, Span::default()))
+            .collect();
+        Bytecode::from_spanned_ast(ast, env)
+    }
+
+    /// Compiles the output of `optimizer::optimize_spanned`, remembering which part of the template each
+    /// instruction came from.
+    pub fn from_spanned_ast<Env: Environment<'a, NumEnum, StrEnum, FilterEnum>>(
+        ast: Vec<(Expr, Span)>,
+        env: &Env,
+    ) -> Result<Bytecode<NumEnum, StrEnum, FilterEnum>, String> {
+        Bytecode::compile_spanned(ast, env).map_err(|(err, _)| err)
+    }
+
+    // the same as from_spanned_ast, but errors carry the span of the expression that caused them
+    pub(crate) fn compile_spanned<Env: Environment<'a, NumEnum, StrEnum, FilterEnum>>(
+        ast: Vec<(Expr, Span)>,
+        env: &Env,
+    ) -> Result<Bytecode<NumEnum, StrEnum, FilterEnum>, (String, Span)> {
         let mut ret_val = Bytecode {
             buffer: None,
             stack: None,
+            scratch: None,
             raw_text: String::new(),
             instructions: vec![],
+            info: vec![],
+            max_stack: 0,
+            saved_nums: 0,
+            saved_texts: 0,
+            constants: vec![],
+            folded_filters: vec![],
         };
 
-        for tree in ast {
-            ret_val.extend_with_tree(tree, env)?;
+        for (tree, span) in ast {
+            let first = ret_val.info.len();
+            ret_val
+                .extend_with_tree(tree, env)
+                .map_err(|err| (err, span))?;
+            for info in &mut ret_val.info[first..] {
+                info.span = span;
+            }
         }
 
+        ret_val.max_stack = ret_val
+            .stack_depth()
+            .map_err(|err| (err, Span::default()))?;
         Ok(ret_val)
     }
 
@@ -118,6 +456,24 @@ impl<
             .collect()
     }
 
+    /// Renders a template across multiple items into an `AsyncWrite`. Items are rendered into an internal buffer,
+    /// which is written out whenever it grows past flush_threshold bytes, and once more after the final item.
+    ///
+    /// Pass `std::slice::from_ref(&item)` to render a single item.
+    #[cfg(feature = "async")]
+    pub fn render_async<'r, RunnerItem, Writer>(
+        &'r self,
+        runner: &'r [RunnerItem],
+        output: &'r mut Writer,
+        flush_threshold: usize,
+    ) -> RenderAsync<'r, NumEnum, StrEnum, FilterEnum, RunnerItem, Writer>
+    where
+        RunnerItem: 'r + Runner<NumEnum, StrEnum, FilterEnum>,
+        Writer: 'r + AsyncWrite + Unpin + ?Sized,
+    {
+        RenderAsync::new(self, runner, output, flush_threshold)
+    }
+
     /// Renders a template using convenient internally-managed buffers, which requires a mutable reference to self.
     pub fn render(
         &mut self,
@@ -147,67 +503,300 @@ impl<
         stack: &mut Vec<f64>,
         buffer: &mut String,
     ) -> Result<(), ::std::io::Error> {
+        self.execute(runner, &mut IoOutput(output), stack, buffer)
+    }
+
+    /// Renders a template using convenient internally-managed buffers, batching the output into vectored writes so
+    /// that an unbuffered `output` sees a handful of `write_vectored` calls instead of one write per substitution.
+    pub fn render_vectored(
+        &mut self,
+        runner: &dyn Runner<NumEnum, StrEnum, FilterEnum>,
+        output: &mut dyn Write,
+    ) -> Result<(), ::std::io::Error> {
+        let mut stack = self.stack.take().unwrap_or_else(|| Vec::with_capacity(8));
+        let mut buffer = self
+            .buffer
+            .take()
+            .unwrap_or_else(|| String::with_capacity(8));
+        let mut scratch = self.scratch.take().unwrap_or_default();
+
+        let result =
+            self.render_vectored_with(runner, output, &mut stack, &mut buffer, &mut scratch);
+
+        self.stack = Some(stack);
+        self.buffer = Some(buffer);
+        self.scratch = Some(scratch);
+
+        result
+    }
+
+    /// The vectored equivalent of `render_with`, using only externally provided buffers. `scratch` holds the output
+    /// which has to be copied before it can be written, such as formatted numbers and filter results.
+    pub fn render_vectored_with(
+        &self,
+        runner: &dyn Runner<NumEnum, StrEnum, FilterEnum>,
+        output: &mut dyn Write,
+        stack: &mut Vec<f64>,
+        buffer: &mut String,
+        scratch: &mut Vec<u8>,
+    ) -> Result<(), ::std::io::Error> {
+        let mut batch = VectoredBatch::new(scratch);
+        let mut texts = SavedText::take(self.saved_texts);
+        let mut stack = Stack::new(stack, self.max_stack, self.saved_nums, &mut texts);
         for instr in &self.instructions {
-            match *instr {
-                Instr::PushImm(val) => stack.push(val),
-                Instr::PushNum(id) => stack.push(runner.num_var(id)),
-                Instr::PrintReg => write!(output, "{}", pop!(stack))?,
-                Instr::PrintRaw(start, end) => {
-                    output.write_all(&self.raw_text[start..end].as_bytes())?
-                }
-                Instr::PrintStr(id) => output.write_all(runner.str_var(id).as_bytes())?,
-                Instr::PrintNum(id) => write!(output, "{}", runner.num_var(id))?,
-                Instr::Add => {
-                    let right = pop!(stack);
-                    let left = pop!(stack);
-                    let result = left + right;
-                    stack.push(result)
-                }
-                Instr::Sub => {
-                    let right = pop!(stack);
-                    let left = pop!(stack);
-                    let result = left - right;
-                    stack.push(result)
-                }
-                Instr::Mul => {
-                    let right = pop!(stack);
-                    let left = pop!(stack);
-                    let result = left * right;
-                    stack.push(result)
-                }
-                Instr::Div => {
-                    let right = pop!(stack);
-                    let left = pop!(stack);
-                    let result = left / right;
-                    stack.push(result)
-                }
-                Instr::CallReg(id, ref args) => {
-                    write!(output, "{}", runner.filter_num(id, args, pop!(stack)))?
-                }
-                Instr::CallId(id, ref args, val_id) => {
-                    buffer.clear();
-                    runner.filter_id(id, args, val_id, &mut *buffer);
-                    write!(output, "{}", buffer)?
-                }
-                Instr::CallStr(id, ref args, val_id) => {
-                    let string = runner.str_var(val_id);
-                    buffer.clear();
-                    runner.filter_str(id, args, string, buffer);
-                    write!(output, "{}", buffer)?
-                }
-                Instr::CallRegStr(id, ref args) => {
-                    //CallRegStr could probably do without this string allocation
-                    let string = pop!(stack).to_string();
-                    buffer.clear();
-                    runner.filter_str(id, args, Cow::from(string), buffer);
-                    write!(output, "{}", buffer)?
+            match self.step(instr, runner, &mut stack, buffer) {
+                Piece::Nothing => continue,
+                Piece::Str(Cow::Borrowed(string)) => batch.push_borrowed(string.as_bytes()),
+                Piece::Str(Cow::Owned(string)) => batch.push_copied(string.as_bytes()),
+                Piece::Num(num) => batch.push_num(num),
+                Piece::Buffer => batch.push_copied(buffer.as_bytes()),
+                Piece::Saved(start, end) => {
+                    batch.push_copied(&stack.texts.text.as_bytes()[start..end])
                 }
             }
+            if batch.is_full() {
+                batch.flush(output)?;
+            }
+        }
+        batch.flush(output)
+    }
+
+    /// Renders a template into a `fmt::Write` sink using convenient internally-managed buffers, which requires a
+    /// mutable reference to self. Unlike `render`, the output stays UTF-8 typed the whole way through.
+    pub fn render_fmt(
+        &mut self,
+        runner: &dyn Runner<NumEnum, StrEnum, FilterEnum>,
+        output: &mut dyn fmt::Write,
+    ) -> fmt::Result {
+        let mut stack = self.stack.take().unwrap_or_else(|| Vec::with_capacity(8));
+        let mut buffer = self
+            .buffer
+            .take()
+            .unwrap_or_else(|| String::with_capacity(8));
+
+        let result = self.render_fmt_with(runner, output, &mut stack, &mut buffer);
+
+        self.stack = Some(stack);
+        self.buffer = Some(buffer);
+
+        result
+    }
+
+    /// The `fmt::Write` equivalent of `render_with`, using only externally provided buffers.
+    pub fn render_fmt_with(
+        &self,
+        runner: &dyn Runner<NumEnum, StrEnum, FilterEnum>,
+        output: &mut dyn fmt::Write,
+        stack: &mut Vec<f64>,
+        buffer: &mut String,
+    ) -> fmt::Result {
+        self.execute(runner, &mut FmtOutput(output), stack, buffer)
+    }
+
+    /// Renders a template into a new `String`, reserving room for all of the template's static text up front.
+    pub fn render_to_string(
+        &mut self,
+        runner: &dyn Runner<NumEnum, StrEnum, FilterEnum>,
+    ) -> String {
+        let mut output = String::with_capacity(self.static_len());
+        self.render_fmt(runner, &mut output)
+            .expect("writing to a String cannot fail");
+        output
+    }
+
+    /// The number of bytes of static text this template prints on every render, which is a lower bound on the
+    /// length of any rendered output.
+    pub fn static_len(&self) -> usize {
+        self.instructions
+            .iter()
+            .map(|instr| match *instr {
+                Instr::PrintRaw(start, end) => end - start,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// The most values the VM's stack holds at any point while rendering this template.
+    pub fn max_stack_depth(&self) -> usize {
+        self.max_stack
+    }
+
+    /// Returns a cursor which renders the template lazily, one chunk of output per call to `next`. Static text is
+    /// borrowed straight out of the template, and strings are borrowed from the runner whenever it allows it.
+    pub fn render_cursor<'r>(
+        &'r self,
+        runner: &'r dyn Runner<NumEnum, StrEnum, FilterEnum>,
+    ) -> RenderCursor<'r, NumEnum, StrEnum, FilterEnum> {
+        RenderCursor {
+            bytecode: self,
+            runner,
+            pc: 0,
+            stack: vec![0.0; self.max_stack + self.saved_nums],
+            stack_len: 0,
+            texts: SavedText::take(self.saved_texts),
+            buffer: String::new(),
+        }
+    }
+
+    fn execute<O: Output>(
+        &self,
+        runner: &dyn Runner<NumEnum, StrEnum, FilterEnum>,
+        output: &mut O,
+        stack: &mut Vec<f64>,
+        buffer: &mut String,
+    ) -> Result<(), O::Error> {
+        let mut texts = SavedText::take(self.saved_texts);
+        let mut stack = Stack::new(stack, self.max_stack, self.saved_nums, &mut texts);
+        for instr in &self.instructions {
+            match self.step(instr, runner, &mut stack, buffer) {
+                Piece::Nothing => {}
+                Piece::Str(string) => output.write_str(&string)?,
+                Piece::Num(num) => output.write_num(num)?,
+                Piece::Buffer => output.write_str(buffer)?,
+                Piece::Saved(start, end) => output.write_str(&stack.texts.text[start..end])?,
+            }
         }
 
         Ok(())
     }
 
+    /// Executes a single instruction, returning whatever it printed.
+    #[inline(always)]
+    fn step<'r>(
+        &'r self,
+        instr: &Instr<NumEnum, StrEnum, FilterEnum>,
+        runner: &'r dyn Runner<NumEnum, StrEnum, FilterEnum>,
+        stack: &mut Stack,
+        buffer: &mut String,
+    ) -> Piece<'r> {
+        match *instr {
+            Instr::PushImm(val) => stack.push(val),
+            Instr::PushNum(id) => stack.push(runner.num_var(id)),
+            Instr::PrintReg => return Piece::Num(stack.pop()),
+            Instr::PrintRaw(start, end) => return Piece::Str(self.raw_text[start..end].into()),
+            Instr::PrintStr(id) => return Piece::Str(runner.str_var(id)),
+            Instr::PrintNum(id) => return Piece::Num(runner.num_var(id)),
+            Instr::Add => {
+                let right = stack.pop();
+                let left = stack.pop();
+                let result = left + right;
+                stack.push(result)
+            }
+            Instr::Sub => {
+                let right = stack.pop();
+                let left = stack.pop();
+                let result = left - right;
+                stack.push(result)
+            }
+            Instr::Mul => {
+                let right = stack.pop();
+                let left = stack.pop();
+                let result = left * right;
+                stack.push(result)
+            }
+            Instr::Div => {
+                let right = stack.pop();
+                let left = stack.pop();
+                let result = left / right;
+                stack.push(result)
+            }
+            Instr::AddImm(right) => {
+                let left = stack.pop();
+                stack.push(left + right)
+            }
+            Instr::SubImm(right) => {
+                let left = stack.pop();
+                stack.push(left - right)
+            }
+            Instr::MulImm(right) => {
+                let left = stack.pop();
+                stack.push(left * right)
+            }
+            Instr::DivImm(right) => {
+                let left = stack.pop();
+                stack.push(left / right)
+            }
+            Instr::Neg => {
+                let val = stack.pop();
+                stack.push(-val)
+            }
+            Instr::CallReg(id, ref args) => {
+                return Piece::Num(runner.filter_num(id, args, stack.pop()))
+            }
+            Instr::CallId(id, ref args, val_id) => {
+                buffer.clear();
+                runner.filter_id(id, args, val_id, &mut *buffer);
+                return Piece::Buffer;
+            }
+            Instr::CallStr(id, ref args, val_id) => {
+                let string = runner.str_var(val_id);
+                buffer.clear();
+                runner.filter_str(id, args, string, buffer);
+                return Piece::Buffer;
+            }
+            Instr::CallRegStr(id, ref args) => {
+                let mut num_buffer = NumBuffer::new();
+                let string = num_buffer.format(stack.pop());
+                buffer.clear();
+                runner.filter_str(id, args, Cow::from(string), buffer);
+                return Piece::Buffer;
+            }
+            Instr::SaveNum(id, slot) => {
+                let val = runner.num_var(id);
+                stack.saved[slot] = val;
+                stack.push(val)
+            }
+            Instr::LoadNum(slot) => {
+                let val = stack.saved[slot];
+                stack.push(val)
+            }
+            Instr::SaveCall(id, ref args, slot) => {
+                let val = runner.filter_num(id, args, stack.pop());
+                stack.saved[slot] = val;
+                return Piece::Num(val);
+            }
+            Instr::PrintSavedNum(slot) => return Piece::Num(stack.saved[slot]),
+            Instr::SaveStr(id, slot) => return stack.texts.save(slot, &runner.str_var(id)),
+            // the filter call this follows has just written its output into the buffer
+            Instr::SaveOutput(slot) => {
+                stack.texts.save(slot, buffer);
+            }
+            Instr::PrintSaved(slot) => {
+                let (start, end) = stack.texts.ranges[slot];
+                return Piece::Saved(start, end);
+            }
+        }
+
+        Piece::Nothing
+    }
+
+    fn push(&mut self, instr: Instr<NumEnum, StrEnum, FilterEnum>) {
+        self.instructions.push(instr);
+        self.info.push(InstrInfo::default());
+    }
+
+    fn push_var(&mut self, instr: Instr<NumEnum, StrEnum, FilterEnum>, var: &str) {
+        self.instructions.push(instr);
+        self.info.push(InstrInfo {
+            var: Some(var.to_string()),
+            ..InstrInfo::default()
+        });
+    }
+
+    fn push_filter(
+        &mut self,
+        instr: Instr<NumEnum, StrEnum, FilterEnum>,
+        filter: &str,
+        var: Option<&str>,
+    ) {
+        self.instructions.push(instr);
+        self.info.push(InstrInfo {
+            var: var.map(|var| var.to_string()),
+            filter: Some(filter.to_string()),
+            ..InstrInfo::default()
+        });
+    }
+
     fn extend_with_tree<Env: Environment<'a, NumEnum, StrEnum, FilterEnum>>(
         &mut self,
         tree: Expr,
@@ -218,28 +807,42 @@ impl<
                 let start = self.raw_text.len();
                 let end = start + string.len();
                 self.raw_text.push_str(string);
-                self.instructions.push(Instr::PrintRaw(start, end));
+                self.push(Instr::PrintRaw(start, end));
             }
             Expr::StringLiteral(string) => {
                 let start = self.raw_text.len();
                 let end = start + string.len();
                 self.raw_text.push_str(&string);
-                self.instructions.push(Instr::PrintRaw(start, end));
+                self.push(Instr::PrintRaw(start, end));
             }
             Expr::Identifier(id) => {
                 if let Some(val) = Env::num_var(id) {
-                    self.instructions.push(Instr::PrintNum(val));
+                    self.push_var(Instr::PrintNum(val), id);
                 } else if let Some(val) = Env::str_var(id) {
-                    self.instructions.push(Instr::PrintStr(val));
+                    self.push_var(Instr::PrintStr(val), id);
                 } else {
                     return Err(format!("Unknown identifier {:?}", id));
                 }
             }
             Expr::Numeric(numeric) => {
                 self.extend_with_numeric(numeric, env)?;
-                self.instructions.push(Instr::PrintReg);
+                self.push(Instr::PrintReg);
             }
             Expr::Filter(id, expr, args) => self.extend_with_filter(id, *expr, args, env)?,
+            Expr::Include(name) => {
+                return Err(format!(
+                    "The partial {:?} was never resolved, templates with includes need compile_with_partials",
+                    name
+                ));
+            }
+            Expr::Extends(name) => {
+                return Err(format!(
+                    "The base template {:?} was never resolved, templates that extend another need compile_with_partials",
+                    name
+                ));
+            }
+            // there is nothing to override a block, so it just renders its contents
+            Expr::Block(_) | Expr::EndBlock => {}
         }
 
         Ok(())
@@ -252,11 +855,11 @@ impl<
     ) -> Result<(), String> {
         match numeric {
             Numeric::Raw(val) => {
-                self.instructions.push(Instr::PushImm(val));
+                self.push(Instr::PushImm(val));
             }
             Numeric::Identifier(id) => {
                 if let Some(val) = Env::num_var(id) {
-                    self.instructions.push(Instr::PushNum(val));
+                    self.push_var(Instr::PushNum(val), id);
                 } else if let Some(_) = Env::str_var(id) {
                     return Err(format!("{:?} is a string, numeric value was expected!", id));
                 } else {
@@ -266,17 +869,17 @@ impl<
             Numeric::Parentheses(expr) => self.extend_with_numeric(*expr, env)?,
             Numeric::Negate(expr) => {
                 self.extend_with_numeric(*expr, env)?;
-                self.instructions.push(Instr::PushImm(-1.0));
-                self.instructions.push(Instr::Mul);
+                self.push(Instr::PushImm(-1.0));
+                self.push(Instr::Mul);
             }
             Numeric::Binary(op, left, right) => {
                 self.extend_with_numeric(*left, env)?;
                 self.extend_with_numeric(*right, env)?;
                 match op {
-                    Operator::Plus => self.instructions.push(Instr::Add),
-                    Operator::Dash => self.instructions.push(Instr::Sub),
-                    Operator::Slash => self.instructions.push(Instr::Div),
-                    Operator::Asterisk => self.instructions.push(Instr::Mul),
+                    Operator::Plus => self.push(Instr::Add),
+                    Operator::Dash => self.push(Instr::Sub),
+                    Operator::Slash => self.push(Instr::Div),
+                    Operator::Asterisk => self.push(Instr::Mul),
                     _ => unreachable!(),
                 }
             }
@@ -292,7 +895,7 @@ impl<
         args: Vec<Literal>,
         env: &Env,
     ) -> Result<(), String> {
-        if let Some((val, arg_count, input_type)) = Env::filter(id) {
+        if let Some((val, arg_count, input_type, _)) = Env::filter(id) {
             if arg_count != args.len() {
                 return Err(format!(
                     "filter {} expected {} args, but {} were provided",
@@ -315,21 +918,21 @@ impl<
             match (input_type, expr) {
                 (FilterInput::Numeric, Expr::Numeric(expr)) => {
                     self.extend_with_numeric(expr, env)?;
-                    self.instructions.push(Instr::CallReg(val, args?));
+                    self.push_filter(Instr::CallReg(val, args?), id, None);
                 }
                 (FilterInput::Stringified, Expr::Numeric(expr)) => {
                     self.extend_with_numeric(expr, env)?;
-                    self.instructions.push(Instr::CallRegStr(val, args?));
+                    self.push_filter(Instr::CallRegStr(val, args?), id, None);
                 }
-                (FilterInput::Numeric, Expr::Identifier(val_id)) => {
-                    if let Some(val_id) = Env::num_var(val_id) {
-                        self.instructions.push(Instr::PushNum(val_id));
-                        self.instructions.push(Instr::CallReg(val, args?));
+                (FilterInput::Numeric, Expr::Identifier(val_name)) => {
+                    if let Some(val_id) = Env::num_var(val_name) {
+                        self.push_var(Instr::PushNum(val_id), val_name);
+                        self.push_filter(Instr::CallReg(val, args?), id, None);
                     } else {
                         return Err(format!(
                             "filter {} expected numeric input expression, found {:#?}",
                             id,
-                            val_id
+                            val_name
                         ));
                     }
                 }
@@ -340,14 +943,14 @@ impl<
                         expr
                     ));
                 }
-                (FilterInput::StrEnumId(valid_ids), Expr::Identifier(val_id)) => {
-                    match Env::str_var(val_id) {
+                (FilterInput::StrEnumId(valid_ids), Expr::Identifier(val_name)) => {
+                    match Env::str_var(val_name) {
                         None => {
                             return Err(format!(
                                 "filter {} expected one of these identifiers: {:#?}.\nUnknown identifier found: {:#?}",
                                 id,
                                 valid_ids,
-                                val_id
+                                val_name
                             ))
                         }
                         Some(val_id) => {
@@ -360,20 +963,20 @@ impl<
                                 ));
                             }
 
-                            self.instructions.push(Instr::CallId(val, args?, val_id));
+                            self.push_filter(Instr::CallId(val, args?, val_id), id, Some(val_name));
                         }
                     }
                 }
-                (FilterInput::Stringified, Expr::Identifier(val_id)) => {
-                    if let Some(val_id) = Env::str_var(val_id) {
-                        self.instructions.push(Instr::CallStr(val, args?, val_id));
-                    } else if let Some(val_id) = Env::num_var(val_id) {
-                        self.instructions.push(Instr::PushNum(val_id));
-                        self.instructions.push(Instr::CallRegStr(val, args?));
+                (FilterInput::Stringified, Expr::Identifier(val_name)) => {
+                    if let Some(val_id) = Env::str_var(val_name) {
+                        self.push_filter(Instr::CallStr(val, args?, val_id), id, Some(val_name));
+                    } else if let Some(val_id) = Env::num_var(val_name) {
+                        self.push_var(Instr::PushNum(val_id), val_name);
+                        self.push_filter(Instr::CallRegStr(val, args?), id, None);
                     } else {
                         return Err(format!(
                             "Unknown identifier {:?} used on filter {:?}",
-                            val_id,
+                            val_name,
                             id
                         ));
                     }
@@ -397,7 +1000,7 @@ impl<
                         id
                     ));
                 }
-                (FilterInput::Stringified, Expr::Raw(_)) => {
+                (FilterInput::Stringified, _) => {
                     unreachable!();
                 }
             }
@@ -408,3 +1011,151 @@ impl<
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use compile;
+    use std::borrow::Cow;
+    use std::fmt::Write;
+    use test_support::*;
+
+    const TEMPLATE: &str =
+        "{{provider}} {{provider_code + 4}} {{id}} {{name | toupper}} {{age | sqrt}} {{weight / 2.2 | round 2}}kg\n";
+    const EXPECTED: &str = "john doe 35 12 BOB 7 77.41kg\n";
+
+    #[test]
+    fn render_to_string() {
+        let env = provider();
+        let mut bytecode = compile(TEMPLATE, &env).unwrap();
+        assert_eq!(bytecode.render_to_string(&person()), EXPECTED);
+    }
+
+    #[test]
+    fn fmt_matches_io() {
+        let env = provider();
+        let mut bytecode = compile(TEMPLATE, &env).unwrap();
+
+        let mut io_output = Vec::new();
+        bytecode.render(&person(), &mut io_output).unwrap();
+
+        let mut fmt_output = String::new();
+        write!(fmt_output, "> ").unwrap();
+        bytecode.render_fmt(&person(), &mut fmt_output).unwrap();
+
+        assert_eq!(
+            fmt_output,
+            format!("> {}", String::from_utf8(io_output).unwrap())
+        );
+    }
+
+    #[test]
+    fn render_cursor() {
+        let env = provider();
+        let bytecode = compile(TEMPLATE, &env).unwrap();
+        let person = person();
+
+        let chunks = bytecode.render_cursor(&person).collect::<Vec<_>>();
+        assert_eq!(
+            chunks,
+            [
+                "john doe 35 ",
+                "12",
+                " ",
+                "BOB",
+                " ",
+                "7",
+                " ",
+                "77.41",
+                "kg\n",
+            ]
+        );
+        assert!(match chunks[0] {
+            Cow::Borrowed(_) => true,
+            Cow::Owned(_) => false,
+        });
+        assert_eq!(chunks.concat(), EXPECTED);
+    }
+
+    #[test]
+    fn comments_merge_text() {
+        let env = provider();
+        let template = "Hi {{! the greeting }}{{name}}, {{!-- \n  {{id}} is unused\n--}}bye";
+        let mut bytecode = compile(template, &env).unwrap();
+        assert_eq!(bytecode.render_to_string(&person()), "Hi Bob, bye");
+        assert_eq!(bytecode.disassemble().len(), 3);
+    }
+
+    #[test]
+    fn escaped_braces() {
+        let env = provider();
+        let mut bytecode = compile(r"\{{name}} \\{{name}} C:\{{name}}", &env).unwrap();
+        assert_eq!(
+            bytecode.render_to_string(&person()),
+            r"{{name}} \Bob C:{{name}}"
+        );
+    }
+
+    #[test]
+    fn string_literals() {
+        let env = provider();
+        let mut bytecode = compile(
+            r#"{{ "{{name}} | \"quoted\"\n" }}{{"\u{e9}" | toupper}}"#,
+            &env,
+        )
+        .unwrap();
+        assert_eq!(
+            bytecode.render_to_string(&person()),
+            "{{name}} | \"quoted\"\nÉ"
+        );
+    }
+
+    #[test]
+    fn static_len() {
+        let env = provider();
+        let bytecode = compile("{{provider}}: {{name}}!", &env).unwrap();
+        assert_eq!(bytecode.static_len(), "john doe: !".len());
+    }
+
+    #[test]
+    fn max_stack_depth() {
+        let env = provider();
+        let mut bytecode = compile("{{age * (id + (weight - 1))}} {{age}}", &env).unwrap();
+        assert_eq!(bytecode.max_stack_depth(), 3);
+
+        // the stack storage passed in is grown to fit, whatever size it starts at
+        let (mut stack, mut buffer) = (Vec::new(), String::new());
+        let mut output = Vec::new();
+        bytecode
+            .render_with(&person(), &mut output, &mut stack, &mut buffer)
+            .unwrap();
+        assert_eq!(output, b"8883.7 49");
+        assert_eq!(bytecode.render_to_string(&person()), "8883.7 49");
+    }
+
+    #[test]
+    fn pure_filters() {
+        let env = provider();
+        let template =
+            "{{provider | toupper}} {{provider_code | sqrt}} {{provider_code + 5 | round 1}}";
+        let mut bytecode = compile(template, &env).unwrap();
+        assert_eq!(
+            bytecode.render_to_string(&person()),
+            "JOHN DOE 5.5677643628300215 36"
+        );
+
+        // the pure filters ran during compilation, leaving only round to be called when rendering
+        let listing = bytecode
+            .disassemble()
+            .into_iter()
+            .map(|instr| instr.instr)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listing,
+            [
+                r#"PrintRaw "JOHN DOE 5.5677643628300215 ""#,
+                "PushImm 36",
+                "CallReg round(1)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+// an alternative backend to the interpreter loop. The bytecode is lowered once into a list of boxed closures, one
+// per piece of output, and rendering an item just calls each of them in turn. Numeric expressions are rebuilt into
+// nested closures along the way, so they are evaluated directly without going through the VM's stack.
+
+use super::{Bytecode, Instr};
+use number::NumBuffer;
+use std::borrow::Cow;
+use std::io::{self, Write};
+use Runner;
+
+type NumFn<'b, NumEnum, StrEnum, FilterEnum> =
+    Box<dyn Fn(&dyn Runner<NumEnum, StrEnum, FilterEnum>) -> f64 + Send + Sync + 'b>;
+
+type PrintFn<'b, NumEnum, StrEnum, FilterEnum> = Box<
+    dyn Fn(&dyn Runner<NumEnum, StrEnum, FilterEnum>, &mut dyn Write, &mut String) -> io::Result<()>
+        + Send
+        + Sync
+        + 'b,
+>;
+
+/// A template lowered into pre-bound closures by `Bytecode::to_closures`. It renders exactly the same output as
+/// the bytecode it was built from, borrowing that bytecode's static text.
+pub struct ClosureProgram<'b, NumEnum, StrEnum, FilterEnum> {
+    prints: Vec<PrintFn<'b, NumEnum, StrEnum, FilterEnum>>,
+    buffer: Option<String>,
+}
+
+impl<NumEnum, StrEnum, FilterEnum> Bytecode<NumEnum, StrEnum, FilterEnum>
+where
+    NumEnum: Copy + Send + Sync,
+    StrEnum: Copy + Send + Sync,
+    FilterEnum: Copy + Send + Sync,
+{
+    /// Lowers this bytecode into a `ClosureProgram`. This is done once up front, and can pay off when the same
+    /// template is rendered for a very large number of items.
+    pub fn to_closures<'b>(&'b self) -> ClosureProgram<'b, NumEnum, StrEnum, FilterEnum> {
+        let mut values: Vec<NumFn<'b, NumEnum, StrEnum, FilterEnum>> =
+            Vec::with_capacity(self.max_stack);
+        let mut prints: Vec<PrintFn<'b, NumEnum, StrEnum, FilterEnum>> = vec![];
+
+        // the stack depth was checked when the bytecode was built, so there is always a value to pop
+        macro_rules! pop {
+            () => {
+                values.pop().expect("stack underflow!")
+            };
+        }
+        macro_rules! binary {
+            ($op:tt) => {{
+                let right = pop!();
+                let left = pop!();
+                values.push(Box::new(move |runner| left(runner) $op right(runner)));
+            }};
+        }
+        macro_rules! immediate {
+            ($op:tt, $right:ident) => {{
+                let left = pop!();
+                values.push(Box::new(move |runner| left(runner) $op $right));
+            }};
+        }
+
+        // the closures have nowhere to save values, so memoised reads go back to the runner
+        let instructions = self.without_saves();
+        for instr in &instructions {
+            match *instr {
+                Instr::PushImm(val) => values.push(Box::new(move |_| val)),
+                Instr::PushNum(id) => values.push(Box::new(move |runner| runner.num_var(id))),
+                Instr::Add => binary!(+),
+                Instr::Sub => binary!(-),
+                Instr::Mul => binary!(*),
+                Instr::Div => binary!(/),
+                Instr::AddImm(right) => immediate!(+, right),
+                Instr::SubImm(right) => immediate!(-, right),
+                Instr::MulImm(right) => immediate!(*, right),
+                Instr::DivImm(right) => immediate!(/, right),
+                Instr::Neg => {
+                    let val = pop!();
+                    values.push(Box::new(move |runner| -val(runner)));
+                }
+                Instr::PrintRaw(start, end) => {
+                    let text = &self.raw_text[start..end];
+                    prints.push(Box::new(move |_, output, _| {
+                        output.write_all(text.as_bytes())
+                    }));
+                }
+                Instr::PrintStr(id) => prints.push(Box::new(move |runner, output, _| {
+                    output.write_all(runner.str_var(id).as_bytes())
+                })),
+                Instr::PrintNum(id) => prints.push(Box::new(move |runner, output, _| {
+                    write_num(output, runner.num_var(id))
+                })),
+                Instr::PrintReg => {
+                    let val = pop!();
+                    prints.push(Box::new(move |runner, output, _| {
+                        write_num(output, val(runner))
+                    }));
+                }
+                Instr::CallReg(id, ref args) => {
+                    let (val, args) = (pop!(), args.clone());
+                    prints.push(Box::new(move |runner, output, _| {
+                        write_num(output, runner.filter_num(id, &args, val(runner)))
+                    }));
+                }
+                Instr::CallId(id, ref args, val_id) => {
+                    let args = args.clone();
+                    prints.push(Box::new(move |runner, output, buffer| {
+                        buffer.clear();
+                        runner.filter_id(id, &args, val_id, buffer);
+                        output.write_all(buffer.as_bytes())
+                    }));
+                }
+                Instr::CallStr(id, ref args, val_id) => {
+                    let args = args.clone();
+                    prints.push(Box::new(move |runner, output, buffer| {
+                        buffer.clear();
+                        runner.filter_str(id, &args, runner.str_var(val_id), buffer);
+                        output.write_all(buffer.as_bytes())
+                    }));
+                }
+                Instr::CallRegStr(id, ref args) => {
+                    let (val, args) = (pop!(), args.clone());
+                    prints.push(Box::new(move |runner, output, buffer| {
+                        let mut num_buffer = NumBuffer::new();
+                        let string = num_buffer.format(val(runner));
+                        buffer.clear();
+                        runner.filter_str(id, &args, Cow::from(string), buffer);
+                        output.write_all(buffer.as_bytes())
+                    }));
+                }
+                Instr::SaveNum(..)
+                | Instr::LoadNum(_)
+                | Instr::SaveCall(..)
+                | Instr::PrintSavedNum(_)
+                | Instr::SaveStr(..)
+                | Instr::SaveOutput(_)
+                | Instr::PrintSaved(_) => unreachable!("memoisation was undone"),
+            }
+        }
+
+        ClosureProgram {
+            prints,
+            buffer: None,
+        }
+    }
+}
+
+impl<'b, NumEnum, StrEnum, FilterEnum> ClosureProgram<'b, NumEnum, StrEnum, FilterEnum> {
+    /// Renders a template using a convenient internally-managed buffer, which requires a mutable reference to self.
+    pub fn render(
+        &mut self,
+        runner: &dyn Runner<NumEnum, StrEnum, FilterEnum>,
+        output: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut buffer = self.buffer.take().unwrap_or_default();
+        let result = self.render_with(runner, output, &mut buffer);
+        self.buffer = Some(buffer);
+        result
+    }
+
+    /// Renders a template using only an externally provided buffer, so it only needs an immutable reference to self
+    /// and can be shared between threads.
+    pub fn render_with(
+        &self,
+        runner: &dyn Runner<NumEnum, StrEnum, FilterEnum>,
+        output: &mut dyn Write,
+        buffer: &mut String,
+    ) -> io::Result<()> {
+        for print in &self.prints {
+            print(runner, output, buffer)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_num(output: &mut dyn Write, num: f64) -> io::Result<()> {
+    output.write_all(NumBuffer::new().format(num).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use compile;
+    use test_support::*;
+
+    #[test]
+    fn matches_interpreter() {
+        let env = provider();
+        let template =
+            "{{provider}} {{provider_code + 4}} {{id}} {{name | toupper}} {{age | sqrt}} \
+             {{-weight / 2.2 * age - id | round 2}} {{age * 2 | toupper}}kg\n";
+        let mut bytecode = compile(template, &env).unwrap();
+        let expected = bytecode.render_to_string(&person());
+
+        let mut program = bytecode.to_closures();
+        let mut output = Vec::new();
+        program.render(&person(), &mut output).unwrap();
+        program.render(&person(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), expected.repeat(2));
+    }
+}
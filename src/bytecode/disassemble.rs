@@ -0,0 +1,177 @@
+// a readable listing of compiled Bytecode, for seeing what a template actually turned into. Variables and filters
+// are shown by the names the template used, raw text is shown inline, and every instruction carries the stack depth
+// it leaves behind and the part of the template it was compiled from.
+
+use super::{Bytecode, Instr};
+use ast::Span;
+use std::fmt::{self, Debug};
+
+/// A single disassembled instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DisassembledInstr {
+    pub index: usize,
+    /// the instruction and its operands, e.g. `PushNum weight` or `PrintRaw "kg\n"`
+    pub instr: String,
+    /// the number of values left on the stack once this instruction has run
+    pub stack_depth: usize,
+    /// the part of the template this instruction came from
+    pub span: Span,
+}
+
+impl fmt::Display for DisassembledInstr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>4}  {:<40} ; depth {}, template {}..{}",
+            self.index, self.instr, self.stack_depth, self.span.start, self.span.end
+        )
+    }
+}
+
+impl<NumEnum: Debug, StrEnum: Debug, FilterEnum: Debug> Bytecode<NumEnum, StrEnum, FilterEnum> {
+    /// Lists the instructions this template compiled to. Each `DisassembledInstr` displays as one line of a
+    /// listing, so the whole program can be printed with
+    /// `for instr in bytecode.disassemble() { println!("{}", instr); }`
+    pub fn disassemble(&self) -> Vec<DisassembledInstr> {
+        let mut depth = 0;
+        self.instructions
+            .iter()
+            .zip(&self.info)
+            .enumerate()
+            .map(|(index, (instr, info))| {
+                // bytecode loaded by from_bytes always has names, but fall back on the enum just in case
+                let var = |val: &dyn Debug| match info.var {
+                    Some(ref var) => var.clone(),
+                    None => format!("{:?}", val),
+                };
+                let filter = |val: &FilterEnum| match info.filter {
+                    Some(ref filter) => filter.clone(),
+                    None => format!("{:?}", val),
+                };
+
+                let text = match *instr {
+                    Instr::PrintRaw(start, end) => {
+                        format!("PrintRaw {:?}", &self.raw_text[start..end])
+                    }
+                    Instr::PrintStr(ref val) => format!("PrintStr {}", var(val)),
+                    Instr::PrintNum(ref val) => format!("PrintNum {}", var(val)),
+                    Instr::PrintReg => "PrintReg".to_string(),
+                    Instr::PushImm(val) => format!("PushImm {}", val),
+                    Instr::PushNum(ref val) => format!("PushNum {}", var(val)),
+                    Instr::CallReg(ref val, ref args) => {
+                        format!("CallReg {}{}", filter(val), format_args(args))
+                    }
+                    Instr::CallId(ref val, ref args, ref input) => {
+                        format!("CallId {}{} {}", filter(val), format_args(args), var(input))
+                    }
+                    Instr::CallStr(ref val, ref args, ref input) => format!(
+                        "CallStr {}{} {}",
+                        filter(val),
+                        format_args(args),
+                        var(input)
+                    ),
+                    Instr::CallRegStr(ref val, ref args) => {
+                        format!("CallRegStr {}{}", filter(val), format_args(args))
+                    }
+                    Instr::Add => "Add".to_string(),
+                    Instr::Sub => "Sub".to_string(),
+                    Instr::Mul => "Mul".to_string(),
+                    Instr::Div => "Div".to_string(),
+                    Instr::AddImm(val) => format!("AddImm {}", val),
+                    Instr::SubImm(val) => format!("SubImm {}", val),
+                    Instr::MulImm(val) => format!("MulImm {}", val),
+                    Instr::DivImm(val) => format!("DivImm {}", val),
+                    Instr::Neg => "Neg".to_string(),
+                    Instr::SaveNum(ref val, slot) => format!("SaveNum {} #{}", var(val), slot),
+                    Instr::LoadNum(slot) => format!("LoadNum #{}", slot),
+                    Instr::SaveCall(ref val, ref args, slot) => {
+                        format!("SaveCall {}{} #{}", filter(val), format_args(args), slot)
+                    }
+                    Instr::PrintSavedNum(slot) => format!("PrintSavedNum #{}", slot),
+                    Instr::SaveStr(ref val, slot) => format!("SaveStr {} #{}", var(val), slot),
+                    Instr::SaveOutput(slot) => format!("SaveOutput #{}", slot),
+                    Instr::PrintSaved(slot) => format!("PrintSaved #{}", slot),
+                };
+
+                let (pops, pushes) = instr.stack_effect();
+                depth = depth - pops + pushes;
+
+                DisassembledInstr {
+                    index,
+                    instr: text,
+                    stack_depth: depth,
+                    span: info.span,
+                }
+            })
+            .collect()
+    }
+}
+
+fn format_args(args: &[f64]) -> String {
+    let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    format!("({})", args.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use compile;
+    use test_support::*;
+
+    #[test]
+    fn listing() {
+        let env = provider();
+        let template = "{{provider}} {{id}} {{name | toupper}} {{weight / 2.2 | round 2}}kg\n";
+        let bytecode = compile(template, &env).unwrap();
+
+        let listing = bytecode
+            .disassemble()
+            .into_iter()
+            .map(|instr| {
+                let source = &template[instr.span.start..instr.span.end];
+                (instr.instr, instr.stack_depth, source)
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            listing,
+            [
+                (r#"PrintRaw "john doe ""#.to_string(), 0, "{{provider}} "),
+                ("PrintNum id".to_string(), 0, "{{id}}"),
+                (r#"PrintRaw " ""#.to_string(), 0, " "),
+                (
+                    "CallStr toupper() name".to_string(),
+                    0,
+                    "{{name | toupper}}"
+                ),
+                (r#"PrintRaw " ""#.to_string(), 0, " "),
+                (
+                    "PushNum weight".to_string(),
+                    1,
+                    "{{weight / 2.2 | round 2}}"
+                ),
+                ("DivImm 2.2".to_string(), 1, "{{weight / 2.2 | round 2}}"),
+                (
+                    "CallReg round(2)".to_string(),
+                    0,
+                    "{{weight / 2.2 | round 2}}"
+                ),
+                (r#"PrintRaw "kg\n""#.to_string(), 0, "kg\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn display() {
+        let env = provider();
+        let bytecode = compile("{{age + 1}}", &env).unwrap();
+        let lines = bytecode
+            .disassemble()
+            .iter()
+            .map(|instr| instr.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "   0  PushNum age                              ; depth 1, template 0..11"
+        );
+    }
+}
@@ -0,0 +1,535 @@
+// per-render memoisation. A template which reads the same variable several times, or calls a pure filter on the
+// same input several times, only needs the runner to produce each of those values once per item. The first use
+// saves the value in a slot, and every later use reads the slot instead of going back to the runner.
+//
+// numbers are saved past the end of the VM's stack, and strings are copied into storage of their own which each
+// thread reuses from one render to the next.
+
+use super::{Bytecode, Instr, InstrInfo};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::mem;
+use Environment;
+
+/// What a slot holds. A filter call's saved number can only be printed, since replaying the call prints it too.
+#[derive(Clone, Copy, PartialEq)]
+enum Slot {
+    Num,
+    Call,
+    Text,
+}
+
+/// Identifies a value by the names the template used for it, so two reads of it can be matched up.
+#[derive(Clone, Hash, PartialEq, Eq)]
+enum Key {
+    Num(String),
+    Str(String),
+    Call(String, Vec<u64>, String),
+}
+
+impl<
+        'a,
+        NumEnum: 'a + Copy + Debug + Send + Sync,
+        StrEnum: 'a + Copy + Debug + Send + Sync + PartialEq,
+        FilterEnum: 'a + Copy + Debug + Send + Sync,
+    > Bytecode<NumEnum, StrEnum, FilterEnum>
+{
+    /// Makes every variable the template reads more than once, and every pure filter it calls more than once with
+    /// the same input, fetch its value from the runner only once per render. Later uses print or push the saved
+    /// value instead.
+    ///
+    /// `compile` already runs this, so it only needs calling on bytecode built with `Bytecode::from_ast`.
+    pub fn memoize<Env: Environment<'a, NumEnum, StrEnum, FilterEnum>>(&mut self) {
+        let instructions = mem::take(&mut self.instructions);
+        let info = mem::take(&mut self.info);
+        let keys = (0..instructions.len())
+            .map(|index| memo_key::<_, _, _, Env>(&instructions, &info, index))
+            .collect::<Vec<_>>();
+        let mut counts = HashMap::new();
+        for key in keys.iter().flatten() {
+            *counts.entry(key).or_insert(0) += 1;
+        }
+
+        // a numeric filter's input is pushed just before the call, and once the call's result is saved, later calls
+        // don't need their input at all
+        let mut dropped = vec![false; instructions.len()];
+        let mut seen = HashSet::new();
+        for (index, key) in keys.iter().enumerate() {
+            if let (&Instr::CallReg(..), Some(key)) = (&instructions[index], key) {
+                if counts[key] > 1 && !seen.insert(key) {
+                    dropped[index - 1] = true;
+                    if let Some(ref input) = keys[index - 1] {
+                        *counts.get_mut(input).unwrap() -= 1;
+                    }
+                }
+            }
+        }
+
+        let mut slots = HashMap::new();
+        let iter = instructions
+            .into_iter()
+            .zip(info)
+            .zip(keys.iter().zip(dropped));
+        for ((instr, info), (key, dropped)) in iter {
+            let key = match *key {
+                _ if dropped => continue,
+                Some(ref key) if counts[key] > 1 => key,
+                _ => {
+                    self.push_info(instr, info);
+                    continue;
+                }
+            };
+
+            if let Some(&slot) = slots.get(key) {
+                match instr {
+                    Instr::PushNum(_) => self.push_info(Instr::LoadNum(slot), info),
+                    Instr::PrintNum(_) => {
+                        self.push_info(Instr::LoadNum(slot), info.clone());
+                        self.push_info(Instr::PrintReg, info);
+                    }
+                    Instr::CallReg(..) => self.push_info(Instr::PrintSavedNum(slot), info),
+                    _ => self.push_info(Instr::PrintSaved(slot), info),
+                }
+                continue;
+            }
+
+            let slot = match instr {
+                Instr::PushNum(id) => {
+                    self.push_info(Instr::SaveNum(id, self.saved_nums), info);
+                    self.saved_nums += 1;
+                    self.saved_nums - 1
+                }
+                Instr::PrintNum(id) => {
+                    self.push_info(Instr::SaveNum(id, self.saved_nums), info.clone());
+                    self.push_info(Instr::PrintReg, info);
+                    self.saved_nums += 1;
+                    self.saved_nums - 1
+                }
+                Instr::CallReg(id, args) => {
+                    self.push_info(Instr::SaveCall(id, args, self.saved_nums), info);
+                    self.saved_nums += 1;
+                    self.saved_nums - 1
+                }
+                Instr::PrintStr(id) => {
+                    self.push_info(Instr::SaveStr(id, self.saved_texts), info);
+                    self.saved_texts += 1;
+                    self.saved_texts - 1
+                }
+                call => {
+                    self.push_info(call, info.clone());
+                    self.push_info(Instr::SaveOutput(self.saved_texts), info);
+                    self.saved_texts += 1;
+                    self.saved_texts - 1
+                }
+            };
+            slots.insert(key.clone(), slot);
+        }
+
+        // splitting a PrintNum in two can make room for one more value on the stack
+        self.max_stack = self
+            .stack_depth()
+            .expect("memoisation unbalanced the stack");
+    }
+}
+
+// the key for the value produced by the instruction at `index`. A numeric filter call is keyed by the variable the
+// instruction before it pushes as its input.
+fn memo_key<'a, NumEnum, StrEnum, FilterEnum, Env>(
+    instructions: &[Instr<NumEnum, StrEnum, FilterEnum>],
+    info: &[InstrInfo],
+    index: usize,
+) -> Option<Key>
+where
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+{
+    let (var, args) = match instructions[index] {
+        Instr::PushNum(_) | Instr::PrintNum(_) => return Some(Key::Num(info[index].var.clone()?)),
+        Instr::PrintStr(_) => return Some(Key::Str(info[index].var.clone()?)),
+        Instr::CallStr(_, ref args, _) | Instr::CallId(_, ref args, _) => {
+            (info[index].var.clone()?, args)
+        }
+        Instr::CallReg(_, ref args) => match instructions[index.checked_sub(1)?] {
+            Instr::PushNum(_) => (info[index - 1].var.clone()?, args),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let filter = info[index].filter.clone()?;
+    match Env::filter(&filter) {
+        Some((_, _, _, true)) => {
+            let args = args.iter().map(|arg| arg.to_bits()).collect();
+            Some(Key::Call(filter, args, var))
+        }
+        _ => None,
+    }
+}
+
+impl<NumEnum, StrEnum, FilterEnum> Bytecode<NumEnum, StrEnum, FilterEnum> {
+    fn push_info(&mut self, instr: Instr<NumEnum, StrEnum, FilterEnum>, info: InstrInfo) {
+        self.instructions.push(instr);
+        self.info.push(info);
+    }
+
+    /// Checks that every saved value is saved exactly once, before anything reads it, and that it is read back as
+    /// the same kind of value. Returns the number of slots the bytecode needs for numbers and for strings.
+    pub(super) fn saved_slots(&self) -> Result<(usize, usize), String> {
+        let mut nums: Vec<Option<Slot>> = vec![];
+        let mut texts: Vec<Option<Slot>> = vec![];
+        for (index, instr) in self.instructions.iter().enumerate() {
+            let prev = index.checked_sub(1).map(|prev| &self.instructions[prev]);
+            let (slots, slot, kind, saving) = match *instr {
+                Instr::SaveNum(_, slot) => (&mut nums, slot, Slot::Num, true),
+                Instr::LoadNum(slot) => (&mut nums, slot, Slot::Num, false),
+                Instr::SaveCall(_, _, slot) => {
+                    // the call is replayed along with the instruction before it, so that has to be its input
+                    match prev {
+                        Some(&Instr::PushImm(_))
+                        | Some(&Instr::PushNum(_))
+                        | Some(&Instr::SaveNum(..))
+                        | Some(&Instr::LoadNum(_)) => {}
+                        _ => return Err("Bytecode saves a filter call with no input".to_string()),
+                    }
+                    (&mut nums, slot, Slot::Call, true)
+                }
+                Instr::PrintSavedNum(slot) => (&mut nums, slot, Slot::Call, false),
+                Instr::SaveStr(_, slot) => (&mut texts, slot, Slot::Text, true),
+                Instr::SaveOutput(slot) => {
+                    match prev {
+                        // a CallRegStr can't be replayed on its own, since it pops a number which is gone by
+                        // the time the saved output is printed, so memoisation never saves its output
+                        Some(&Instr::CallId(..)) | Some(&Instr::CallStr(..)) => {}
+                        _ => return Err("Bytecode saves output which no filter wrote".to_string()),
+                    }
+                    (&mut texts, slot, Slot::Text, true)
+                }
+                Instr::PrintSaved(slot) => (&mut texts, slot, Slot::Text, false),
+                _ => continue,
+            };
+
+            if slots.len() <= slot {
+                slots.resize(slot + 1, None);
+            }
+            if saving {
+                if slots[slot].is_some() {
+                    return Err(format!("Bytecode saves slot {} twice", slot));
+                }
+                slots[slot] = Some(kind);
+            } else if slots[slot] != Some(kind) {
+                return Err(format!("Bytecode reads slot {} before saving it", slot));
+            }
+        }
+
+        Ok((nums.len(), texts.len()))
+    }
+}
+
+impl<NumEnum: Copy, StrEnum: Copy, FilterEnum: Copy> Bytecode<NumEnum, StrEnum, FilterEnum> {
+    /// The instructions with memoisation undone, so every use of a value fetches it from the runner again. This is
+    /// for backends which don't have anywhere to save values.
+    pub(super) fn without_saves(&self) -> Vec<Instr<NumEnum, StrEnum, FilterEnum>> {
+        // the instructions which produce each saved value again
+        let mut nums = HashMap::new();
+        let mut texts = HashMap::new();
+        let mut instructions: Vec<Instr<NumEnum, StrEnum, FilterEnum>> = vec![];
+        for instr in &self.instructions {
+            match *instr {
+                Instr::SaveNum(id, slot) => {
+                    nums.insert(slot, vec![Instr::PushNum(id)]);
+                    instructions.push(Instr::PushNum(id));
+                }
+                Instr::SaveCall(id, ref args, slot) => {
+                    let input = instructions
+                        .last()
+                        .expect("a saved filter call always follows its input")
+                        .clone();
+                    nums.insert(slot, vec![input, Instr::CallReg(id, args.clone())]);
+                    instructions.push(Instr::CallReg(id, args.clone()));
+                }
+                Instr::SaveStr(id, slot) => {
+                    texts.insert(slot, vec![Instr::PrintStr(id)]);
+                    instructions.push(Instr::PrintStr(id));
+                }
+                Instr::SaveOutput(slot) => {
+                    let call = instructions
+                        .last()
+                        .expect("saved output always follows a filter call")
+                        .clone();
+                    texts.insert(slot, vec![call]);
+                }
+                Instr::LoadNum(slot) | Instr::PrintSavedNum(slot) => {
+                    instructions.extend(nums[&slot].iter().cloned())
+                }
+                Instr::PrintSaved(slot) => instructions.extend(texts[&slot].iter().cloned()),
+                ref instr => instructions.push(instr.clone()),
+            }
+        }
+        instructions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytecode::{Bytecode, Instr};
+    use compile;
+    use std::borrow::Cow;
+    use std::cell::Cell;
+    use test_support::*;
+    use Runner;
+
+    // counts how often the runner is asked for a value
+    struct Counting {
+        person: Person,
+        reads: Cell<usize>,
+        calls: Cell<usize>,
+    }
+
+    impl Runner<PersonNums, PersonStrs, PersonFilters> for Counting {
+        fn num_var(&self, var: PersonNums) -> f64 {
+            self.reads.set(self.reads.get() + 1);
+            self.person.num_var(var)
+        }
+
+        fn str_var(&self, var: PersonStrs) -> Cow<'_, str> {
+            self.reads.set(self.reads.get() + 1);
+            self.person.str_var(var)
+        }
+
+        fn filter_num(&self, filter: PersonFilters, args: &[f64], input: f64) -> f64 {
+            self.calls.set(self.calls.get() + 1);
+            self.person.filter_num(filter, args, input)
+        }
+
+        fn filter_id(
+            &self,
+            filter: PersonFilters,
+            args: &[f64],
+            input: PersonStrs,
+            buffer: &mut String,
+        ) {
+            self.calls.set(self.calls.get() + 1);
+            self.person.filter_id(filter, args, input, buffer)
+        }
+
+        fn filter_str(
+            &self,
+            filter: PersonFilters,
+            args: &[f64],
+            input: Cow<'_, str>,
+            buffer: &mut String,
+        ) {
+            self.calls.set(self.calls.get() + 1);
+            self.person.filter_str(filter, args, input, buffer)
+        }
+    }
+
+    const TEMPLATE: &str =
+        "{{name}} {{name | toupper}} {{age}} {{name}} {{age + 1}} {{name | toupper}} {{id}} {{name}}";
+    const EXPECTED: &str = "Bob BOB 49 Bob 50 BOB 12 Bob";
+
+    fn counting() -> Counting {
+        Counting {
+            person: person(),
+            reads: Cell::new(0),
+            calls: Cell::new(0),
+        }
+    }
+
+    #[test]
+    fn reads_once() {
+        let env = provider();
+        let mut bytecode = compile(TEMPLATE, &env).unwrap();
+        let runner = counting();
+        assert_eq!(bytecode.render_to_string(&runner), EXPECTED);
+        // name, age and id are each read once, plus name again as the input of the first toupper
+        assert_eq!((runner.reads.get(), runner.calls.get()), (4, 1));
+
+        let listing = bytecode
+            .disassemble()
+            .into_iter()
+            .map(|instr| instr.instr)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listing,
+            [
+                "SaveStr name #0",
+                r#"PrintRaw " ""#,
+                "CallStr toupper() name",
+                "SaveOutput #1",
+                r#"PrintRaw " ""#,
+                "SaveNum age #0",
+                "PrintReg",
+                r#"PrintRaw " ""#,
+                "PrintSaved #0",
+                r#"PrintRaw " ""#,
+                "LoadNum #0",
+                "AddImm 1",
+                "PrintReg",
+                r#"PrintRaw " ""#,
+                "PrintSaved #1",
+                r#"PrintRaw " ""#,
+                "PrintNum id",
+                r#"PrintRaw " ""#,
+                "PrintSaved #0",
+            ]
+        );
+    }
+
+    #[test]
+    fn every_render_path() {
+        let env = provider();
+        let mut bytecode = compile(TEMPLATE, &env).unwrap();
+        let runner = counting();
+
+        // the same buffers are reused across renders, and saved values never leak from one render into the next
+        let (mut stack, mut buffer) = (Vec::new(), String::new());
+        for _ in 0..2 {
+            let mut output = Vec::new();
+            bytecode
+                .render_with(&runner, &mut output, &mut stack, &mut buffer)
+                .unwrap();
+            assert_eq!(output, EXPECTED.as_bytes());
+        }
+
+        let mut output = Vec::new();
+        bytecode.render_vectored(&runner, &mut output).unwrap();
+        assert_eq!(output, EXPECTED.as_bytes());
+
+        let chunks = bytecode.render_cursor(&runner).collect::<Vec<_>>();
+        assert_eq!(chunks.concat(), EXPECTED);
+
+        let mut output = Vec::new();
+        bytecode.to_closures().render(&runner, &mut output).unwrap();
+        assert_eq!(output, EXPECTED.as_bytes());
+
+        let mut loaded = Bytecode::from_bytes(&bytecode.to_bytes(), &env).unwrap();
+        assert_eq!(loaded.render_to_string(&runner), EXPECTED);
+
+        let mut specialized = bytecode.specialize(&[(PersonNums::Age, 49.0)], &[]);
+        assert_eq!(specialized.render_to_string(&runner), EXPECTED);
+    }
+
+    // a filter which replaces whatever is in its buffer, which it's allowed to do since the buffer starts out empty
+    struct Overwriting {
+        person: Person,
+        shorten: bool,
+    }
+
+    impl Runner<PersonNums, PersonStrs, PersonFilters> for Overwriting {
+        fn num_var(&self, var: PersonNums) -> f64 {
+            self.person.num_var(var)
+        }
+
+        fn str_var(&self, var: PersonStrs) -> Cow<'_, str> {
+            self.person.str_var(var)
+        }
+
+        fn filter_num(&self, filter: PersonFilters, args: &[f64], input: f64) -> f64 {
+            self.person.filter_num(filter, args, input)
+        }
+
+        fn filter_id(&self, _: PersonFilters, _: &[f64], _: PersonStrs, _: &mut String) {
+            unreachable!()
+        }
+
+        fn filter_str(
+            &self,
+            _: PersonFilters,
+            _: &[f64],
+            input: Cow<'_, str>,
+            buffer: &mut String,
+        ) {
+            if self.shorten {
+                buffer.clear();
+                buffer.push_str(&input[..1]);
+            } else {
+                *buffer = input.to_uppercase();
+            }
+        }
+    }
+
+    #[test]
+    fn filters_own_their_buffer() {
+        let env = provider();
+        let mut bytecode = compile(
+            "{{name}} {{name | toupper}} {{name}} {{name | toupper}}",
+            &env,
+        )
+        .unwrap();
+        for &(shorten, expected) in &[(false, "Bob BOB Bob BOB"), (true, "Bob B Bob B")] {
+            let runner = Overwriting {
+                person: person(),
+                shorten,
+            };
+            assert_eq!(bytecode.render_to_string(&runner), expected);
+
+            let mut output = Vec::new();
+            bytecode.render_vectored(&runner, &mut output).unwrap();
+            assert_eq!(output, expected.as_bytes());
+
+            let chunks = bytecode.render_cursor(&runner).collect::<Vec<_>>();
+            assert_eq!(chunks.concat(), expected);
+        }
+    }
+
+    #[test]
+    fn impure_filters_still_run() {
+        let env = provider();
+        let mut bytecode = compile("{{weight | round 1}} {{weight | round 1}}", &env).unwrap();
+        let runner = counting();
+        assert_eq!(bytecode.render_to_string(&runner), "170.3 170.3");
+        assert_eq!((runner.reads.get(), runner.calls.get()), (1, 2));
+    }
+
+    #[test]
+    fn numeric_filters() {
+        let env = provider();
+        let mut bytecode = compile("{{age | sqrt}} {{age}} {{age | sqrt}}", &env).unwrap();
+        let runner = counting();
+        assert_eq!(bytecode.render_to_string(&runner), "7 49 7");
+        assert_eq!((runner.reads.get(), runner.calls.get()), (1, 1));
+
+        let listing = bytecode
+            .disassemble()
+            .into_iter()
+            .map(|instr| instr.instr)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listing,
+            [
+                "SaveNum age #0",
+                "SaveCall sqrt() #1",
+                r#"PrintRaw " ""#,
+                "LoadNum #0",
+                "PrintReg",
+                r#"PrintRaw " ""#,
+                "PrintSavedNum #1",
+            ]
+        );
+
+        let mut output = Vec::new();
+        bytecode.to_closures().render(&runner, &mut output).unwrap();
+        assert_eq!(output, b"7 49 7");
+
+        let mut loaded = Bytecode::from_bytes(&bytecode.to_bytes(), &env).unwrap();
+        assert_eq!(loaded.render_to_string(&runner), "7 49 7");
+
+        let mut specialized = bytecode.specialize(&[(PersonNums::Age, 16.0)], &[]);
+        assert_eq!(specialized.render_to_string(&runner), "4 16 4");
+    }
+
+    #[test]
+    fn rejects_saved_numeric_calls() {
+        let env = provider();
+        let mut bytecode = compile("{{age | toupper}}", &env).unwrap();
+        let info = bytecode.info[0].clone();
+        bytecode.push_info(Instr::SaveOutput(0), info.clone());
+        bytecode.push_info(Instr::PrintSaved(0), info);
+
+        let err = Bytecode::from_bytes(&bytecode.to_bytes(), &env).unwrap_err();
+        assert_eq!(err, "Bytecode saves output which no filter wrote");
+    }
+}
@@ -0,0 +1,125 @@
+// rewrites common instruction sequences from codegen into single fused instructions, so the render loop dispatches
+// fewer times per item. Every rewrite only ever looks at the last instruction kept and the next one coming in.
+
+use super::{Bytecode, Instr};
+use std::mem;
+
+impl<NumEnum: Copy, StrEnum, FilterEnum> Bytecode<NumEnum, StrEnum, FilterEnum> {
+    /// Fuses instruction pairs into superinstructions:
+    ///
+    /// - `PushImm(x); Add` and friends become `AddImm(x)`, `SubImm(x)`, `MulImm(x)` and `DivImm(x)`
+    /// - `PushImm(-1); Mul`, which is how negation is compiled, becomes `Neg`
+    /// - `PushNum(var); PrintReg` becomes `PrintNum(var)`
+    /// - `PrintRaw` instructions over adjacent raw text become one `PrintRaw`
+    ///
+    /// `compile` already runs this, so it only needs calling on bytecode built with `Bytecode::from_ast`.
+    pub fn peephole(&mut self) {
+        let instructions = mem::take(&mut self.instructions);
+        let info = mem::take(&mut self.info);
+
+        for (instr, info) in instructions.into_iter().zip(info) {
+            let fused = match (self.instructions.last(), &instr) {
+                (Some(&Instr::PushImm(-1.0)), &Instr::Mul) => Some(Instr::Neg),
+                (Some(&Instr::PushImm(val)), &Instr::Add) => Some(Instr::AddImm(val)),
+                (Some(&Instr::PushImm(val)), &Instr::Sub) => Some(Instr::SubImm(val)),
+                (Some(&Instr::PushImm(val)), &Instr::Mul) => Some(Instr::MulImm(val)),
+                (Some(&Instr::PushImm(val)), &Instr::Div) => Some(Instr::DivImm(val)),
+                (Some(&Instr::PushNum(id)), &Instr::PrintReg) => Some(Instr::PrintNum(id)),
+                (Some(&Instr::PrintRaw(start, prev_end)), &Instr::PrintRaw(next_start, end))
+                    if prev_end == next_start =>
+                {
+                    Some(Instr::PrintRaw(start, end))
+                }
+                _ => None,
+            };
+
+            match fused {
+                Some(fused) => {
+                    // the instruction being replaced keeps its names, whichever of the pair had them
+                    let last = self.info.len() - 1;
+                    self.instructions[last] = fused;
+                    let prev = &mut self.info[last];
+                    prev.span = prev.span.to(info.span);
+                    if prev.var.is_none() {
+                        prev.var = info.var;
+                    }
+                }
+                None => {
+                    self.instructions.push(instr);
+                    self.info.push(info);
+                }
+            }
+        }
+
+        // fusing never deepens the stack, but it can make it shallower
+        self.max_stack = self
+            .stack_depth()
+            .expect("the peephole pass unbalanced the stack");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ast;
+    use bytecode::Bytecode;
+    use compile;
+    use optimizer;
+    use test_support::*;
+    use tokenizer::Tokenizer;
+
+    fn listing(template: &str) -> Vec<String> {
+        let env = provider();
+        compile(template, &env)
+            .unwrap()
+            .disassemble()
+            .into_iter()
+            .map(|instr| instr.instr)
+            .collect()
+    }
+
+    #[test]
+    fn fuses_immediates() {
+        assert_eq!(
+            listing("{{age * 1.5 + id}}{{-weight / 2}}{{id - 1 | round 0}}"),
+            [
+                "PushNum age",
+                "MulImm 1.5",
+                "SaveNum id #0",
+                "Add",
+                "PrintReg",
+                "PushNum weight",
+                "DivImm 2",
+                "Neg",
+                "PrintReg",
+                "LoadNum #0",
+                "SubImm 1",
+                "CallReg round(0)",
+            ]
+        );
+    }
+
+    #[test]
+    fn fuses_prints() {
+        assert_eq!(
+            listing("{{weight}} and {{(age)}}"),
+            ["PrintNum weight", "PrintRaw \" and \"", "PrintNum age"]
+        );
+    }
+
+    #[test]
+    fn renders_the_same() {
+        let env = provider();
+        let template =
+            "{{provider}} {{-age}} {{age * -1}} {{(id)}} {{weight / 2.2 | round 2}} {{id - 0.5 | toupper}}\n";
+
+        let ast = optimizer::optimize(ast::parse(Tokenizer::new(template)).unwrap(), &env);
+        let mut naive = Bytecode::from_ast(ast, &env).unwrap();
+        let mut fused = compile(template, &env).unwrap();
+        assert!(fused.disassemble().len() < naive.disassemble().len());
+
+        assert_eq!(
+            fused.render_to_string(&person()),
+            naive.render_to_string(&person())
+        );
+    }
+}
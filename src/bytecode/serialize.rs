@@ -0,0 +1,920 @@
+// a compact binary encoding of compiled Bytecode, so templates don't have to be recompiled at every start up.
+//
+// the layout is the magic bytes, the format version, the raw text, the environment constants, the folded filter
+// calls and then the instructions, each of which is an opcode followed by its operands and the span of the template
+// it was compiled from. Integers are LEB128 varints and floats are little-endian bit patterns.
+// Variables and filters are stored by the name they had in the template, and resolved against the Environment
+// again when the bytecode is loaded. Environment constants are folded in during compilation, so the name and value
+// of each one is stored too, and loading fails if the Environment doesn't have the same value for it anymore.
+// Pure filters whose input was constant are run at compile time as well, so each of those calls is stored with its
+// input and output, and loading runs it again and fails if the Environment gives a different output.
+
+use super::{Bytecode, Instr, InstrInfo};
+use ast::{Expr, Literal, Numeric, Span};
+use optimizer;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt::{self, Debug};
+use {Environment, FilterInput};
+
+const MAGIC: &[u8; 4] = b"ZAPR";
+
+/// Bumped whenever the encoding changes. Bytecode saved with any other version is rejected when loading.
+pub const FORMAT_VERSION: u64 = 7;
+
+const PRINT_RAW: u8 = 0;
+const PRINT_STR: u8 = 1;
+const PRINT_NUM: u8 = 2;
+const PRINT_REG: u8 = 3;
+const PUSH_IMM: u8 = 4;
+const PUSH_NUM: u8 = 5;
+const CALL_REG: u8 = 6;
+const CALL_ID: u8 = 7;
+const CALL_STR: u8 = 8;
+const CALL_REG_STR: u8 = 9;
+const ADD: u8 = 10;
+const SUB: u8 = 11;
+const MUL: u8 = 12;
+const DIV: u8 = 13;
+const ADD_IMM: u8 = 14;
+const SUB_IMM: u8 = 15;
+const MUL_IMM: u8 = 16;
+const DIV_IMM: u8 = 17;
+const NEG: u8 = 18;
+const SAVE_NUM: u8 = 19;
+const LOAD_NUM: u8 = 20;
+const SAVE_STR: u8 = 21;
+const SAVE_OUTPUT: u8 = 22;
+const PRINT_SAVED: u8 = 23;
+const SAVE_CALL: u8 = 24;
+const PRINT_SAVED_NUM: u8 = 25;
+
+const CONSTANT_NUM: u8 = 0;
+const CONSTANT_STR: u8 = 1;
+
+/// The value an environment constant had when it was folded into a template.
+#[derive(Clone, Debug)]
+pub enum Constant {
+    Num(f64),
+    Str(String),
+}
+
+impl PartialEq for Constant {
+    // NaN constants still have to match themselves
+    fn eq(&self, other: &Constant) -> bool {
+        match (self, other) {
+            (Constant::Num(a), Constant::Num(b)) => a.to_bits() == b.to_bits(),
+            (Constant::Str(a), Constant::Str(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Constant::Num(val) => write!(f, "{}", val),
+            Constant::Str(ref val) => write!(f, "{:?}", val),
+        }
+    }
+}
+
+/// A pure filter call that was run at compile time, with the input it was given and the output that replaced it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FoldedFilter {
+    name: String,
+    args: Vec<f64>,
+    input: Constant,
+    output: Constant,
+}
+
+/// Finds every environment constant the template refers to, before the optimizer folds them in. This looks them up
+/// the same way `optimizer::run_passes` does, so the values match what ends up in the bytecode.
+pub fn folded_constants<'a, NumEnum, StrEnum, FilterEnum, Env>(
+    ast: &[(Expr<'a>, Span)],
+    env: &'a Env,
+) -> Vec<(String, Constant)>
+where
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+{
+    let mut constants = Vec::new();
+    for (tree, _) in ast {
+        tree_constants(tree, env, &mut constants);
+    }
+    constants
+}
+
+fn tree_constants<'a, NumEnum, StrEnum, FilterEnum, Env>(
+    tree: &Expr<'a>,
+    env: &'a Env,
+    constants: &mut Vec<(String, Constant)>,
+) where
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+{
+    match *tree {
+        Expr::Identifier(id) => {
+            if let Some(constant) = constant(id, env) {
+                add_constant(id, constant, constants);
+            }
+        }
+        Expr::Numeric(ref numeric) => numeric_constants(numeric, env, constants),
+        Expr::Filter(_, ref expr, _) => tree_constants(expr, env, constants),
+        _ => {}
+    }
+}
+
+fn numeric_constants<'a, NumEnum, StrEnum, FilterEnum, Env>(
+    numeric: &Numeric<'a>,
+    env: &'a Env,
+    constants: &mut Vec<(String, Constant)>,
+) where
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+{
+    match *numeric {
+        Numeric::Identifier(id) => {
+            if let Some(val) = env.num_constant(id) {
+                add_constant(id, Constant::Num(val), constants);
+            }
+        }
+        Numeric::Binary(_, ref left, ref right) => {
+            numeric_constants(left, env, constants);
+            numeric_constants(right, env, constants);
+        }
+        Numeric::Negate(ref expr) | Numeric::Parentheses(ref expr) => {
+            numeric_constants(expr, env, constants)
+        }
+        Numeric::Raw(_) => {}
+    }
+}
+
+fn add_constant(name: &str, constant: Constant, constants: &mut Vec<(String, Constant)>) {
+    if constants.iter().all(|(seen, _)| seen != name) {
+        constants.push((name.to_string(), constant));
+    }
+}
+
+/// Finds every pure filter call the optimizer is able to run at compile time. Each filter's input is folded and the
+/// filter evaluated with the same functions `optimizer::optimize_tree` uses, so the outputs match what ends up in
+/// the bytecode.
+pub fn folded_filters<'a, NumEnum, StrEnum, FilterEnum, Env>(
+    ast: &[(Expr<'a>, Span)],
+    env: &'a Env,
+    effort: u32,
+) -> Vec<FoldedFilter>
+where
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+{
+    let mut filters = Vec::new();
+    if effort > 0 {
+        for (tree, _) in ast {
+            tree_filters(tree, env, effort, &mut filters);
+        }
+    }
+    filters
+}
+
+fn tree_filters<'a, NumEnum, StrEnum, FilterEnum, Env>(
+    tree: &Expr<'a>,
+    env: &'a Env,
+    effort: u32,
+    filters: &mut Vec<FoldedFilter>,
+) where
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+{
+    if let Expr::Filter(id, ref input, ref args) = *tree {
+        // nested calls feed their output into this one, so they are recorded first
+        tree_filters(input, env, effort, filters);
+        let input = optimizer::optimize_filter_input((**input).clone(), env, effort);
+        let output = optimizer::evaluate_pure_filter(id, &input, args, env);
+        if let (Some(input), Some(output)) = (folded(&input), output.as_ref().and_then(folded)) {
+            let filter = FoldedFilter {
+                name: id.to_string(),
+                args: args
+                    .iter()
+                    .filter_map(|arg| match *arg {
+                        Literal::Number(val) => Some(val),
+                        Literal::StringLiteral(_) => None,
+                    })
+                    .collect(),
+                input,
+                output,
+            };
+            if !filters.contains(&filter) {
+                filters.push(filter);
+            }
+        }
+    }
+}
+
+// the constant a folded expression was reduced to, if it was
+fn folded(expr: &Expr) -> Option<Constant> {
+    match *expr {
+        Expr::Numeric(Numeric::Raw(val)) => Some(Constant::Num(val)),
+        Expr::StringLiteral(ref val) => Some(Constant::Str(val.to_string())),
+        _ => None,
+    }
+}
+
+// runs a folded filter call again the way the optimizer did, giving the output this environment has for it
+fn run_filter<'a, NumEnum, StrEnum, FilterEnum, Env>(
+    filter: &FoldedFilter,
+    env: &'a Env,
+) -> Option<Constant>
+where
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+{
+    let input = match filter.input {
+        Constant::Num(val) => Expr::Numeric(Numeric::Raw(val)),
+        Constant::Str(ref val) => Expr::StringLiteral(Cow::Owned(val.clone())),
+    };
+    let args = filter
+        .args
+        .iter()
+        .map(|&arg| Literal::Number(arg))
+        .collect::<Vec<_>>();
+    optimizer::evaluate_pure_filter(&filter.name, &input, &args, env)
+        .as_ref()
+        .and_then(folded)
+}
+
+// the value `name` has in the environment, preferring a number like the optimizer does
+fn constant<'a, NumEnum, StrEnum, FilterEnum, Env>(name: &str, env: &'a Env) -> Option<Constant>
+where
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+{
+    match env.num_constant(name) {
+        Some(val) => Some(Constant::Num(val)),
+        None => env
+            .str_constant(name)
+            .map(|val| Constant::Str(val.into_owned())),
+    }
+}
+
+impl<
+        'a,
+        NumEnum: 'a + Copy + Debug + Send + Sync,
+        StrEnum: 'a + Copy + Debug + Send + Sync + PartialEq,
+        FilterEnum: 'a + Copy + Debug + Send + Sync,
+    > Bytecode<NumEnum, StrEnum, FilterEnum>
+{
+    // keeps the constants and filter calls that `folded_constants` and `folded_filters` found in the template this
+    // was compiled from
+    pub(crate) fn record_constants(
+        &mut self,
+        constants: Vec<(String, Constant)>,
+        folded_filters: Vec<FoldedFilter>,
+    ) {
+        self.constants = constants;
+        self.folded_filters = folded_filters;
+    }
+
+    /// Encodes this bytecode into a versioned binary format which can be loaded again with `from_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16 + self.raw_text.len() + 4 * self.instructions.len());
+        out.extend_from_slice(MAGIC);
+        write_uint(&mut out, FORMAT_VERSION);
+        write_str(&mut out, &self.raw_text);
+
+        write_uint(&mut out, self.constants.len() as u64);
+        for (name, constant) in &self.constants {
+            write_str(&mut out, name);
+            write_constant(&mut out, constant);
+        }
+
+        write_uint(&mut out, self.folded_filters.len() as u64);
+        for filter in &self.folded_filters {
+            write_str(&mut out, &filter.name);
+            write_args(&mut out, &filter.args);
+            write_constant(&mut out, &filter.input);
+            write_constant(&mut out, &filter.output);
+        }
+
+        write_uint(&mut out, self.instructions.len() as u64);
+
+        for (instr, info) in self.instructions.iter().zip(&self.info) {
+            let var = || {
+                info.var
+                    .as_ref()
+                    .expect("bytecode is missing a variable name")
+            };
+            let filter = || {
+                info.filter
+                    .as_ref()
+                    .expect("bytecode is missing a filter name")
+            };
+            match *instr {
+                Instr::PrintRaw(start, end) => {
+                    out.push(PRINT_RAW);
+                    write_uint(&mut out, start as u64);
+                    write_uint(&mut out, end as u64);
+                }
+                Instr::PrintStr(_) => {
+                    out.push(PRINT_STR);
+                    write_str(&mut out, var());
+                }
+                Instr::PrintNum(_) => {
+                    out.push(PRINT_NUM);
+                    write_str(&mut out, var());
+                }
+                Instr::PrintReg => out.push(PRINT_REG),
+                Instr::PushImm(val) => {
+                    out.push(PUSH_IMM);
+                    write_num(&mut out, val);
+                }
+                Instr::PushNum(_) => {
+                    out.push(PUSH_NUM);
+                    write_str(&mut out, var());
+                }
+                Instr::CallReg(_, ref args) => {
+                    out.push(CALL_REG);
+                    write_str(&mut out, filter());
+                    write_args(&mut out, args);
+                }
+                Instr::CallId(_, ref args, _) => {
+                    out.push(CALL_ID);
+                    write_str(&mut out, filter());
+                    write_args(&mut out, args);
+                    write_str(&mut out, var());
+                }
+                Instr::CallStr(_, ref args, _) => {
+                    out.push(CALL_STR);
+                    write_str(&mut out, filter());
+                    write_args(&mut out, args);
+                    write_str(&mut out, var());
+                }
+                Instr::CallRegStr(_, ref args) => {
+                    out.push(CALL_REG_STR);
+                    write_str(&mut out, filter());
+                    write_args(&mut out, args);
+                }
+                Instr::Add => out.push(ADD),
+                Instr::Sub => out.push(SUB),
+                Instr::Mul => out.push(MUL),
+                Instr::Div => out.push(DIV),
+                Instr::AddImm(val) => {
+                    out.push(ADD_IMM);
+                    write_num(&mut out, val);
+                }
+                Instr::SubImm(val) => {
+                    out.push(SUB_IMM);
+                    write_num(&mut out, val);
+                }
+                Instr::MulImm(val) => {
+                    out.push(MUL_IMM);
+                    write_num(&mut out, val);
+                }
+                Instr::DivImm(val) => {
+                    out.push(DIV_IMM);
+                    write_num(&mut out, val);
+                }
+                Instr::Neg => out.push(NEG),
+                Instr::SaveNum(_, slot) => {
+                    out.push(SAVE_NUM);
+                    write_str(&mut out, var());
+                    write_uint(&mut out, slot as u64);
+                }
+                Instr::LoadNum(slot) => {
+                    out.push(LOAD_NUM);
+                    write_uint(&mut out, slot as u64);
+                }
+                Instr::SaveCall(_, ref args, slot) => {
+                    out.push(SAVE_CALL);
+                    write_str(&mut out, filter());
+                    write_args(&mut out, args);
+                    write_uint(&mut out, slot as u64);
+                }
+                Instr::PrintSavedNum(slot) => {
+                    out.push(PRINT_SAVED_NUM);
+                    write_uint(&mut out, slot as u64);
+                }
+                Instr::SaveStr(_, slot) => {
+                    out.push(SAVE_STR);
+                    write_str(&mut out, var());
+                    write_uint(&mut out, slot as u64);
+                }
+                Instr::SaveOutput(slot) => {
+                    out.push(SAVE_OUTPUT);
+                    write_uint(&mut out, slot as u64);
+                }
+                Instr::PrintSaved(slot) => {
+                    out.push(PRINT_SAVED);
+                    write_uint(&mut out, slot as u64);
+                }
+            }
+            write_uint(&mut out, info.span.start as u64);
+            write_uint(&mut out, info.span.end as u64);
+        }
+
+        out
+    }
+
+    /// Loads bytecode produced by `to_bytes`, resolving every variable and filter name against `Env`. Loading fails
+    /// if the data was written by a different format version, if a name is unknown to this environment, if a
+    /// filter's argument count or input type no longer matches the way the template uses it, if a filter whose
+    /// result was memoised is no longer pure, if `env` has a different value for any of the constants that were
+    /// folded into the bytecode, or if a pure filter that was run at compile time gives a different output in `env`.
+    pub fn from_bytes<Env: Environment<'a, NumEnum, StrEnum, FilterEnum>>(
+        bytes: &[u8],
+        env: &'a Env,
+    ) -> Result<Bytecode<NumEnum, StrEnum, FilterEnum>, String> {
+        let mut reader = Reader { bytes };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err("Not a zapper bytecode file".to_string());
+        }
+        let version = reader.uint()?;
+        if version != FORMAT_VERSION {
+            return Err(format!(
+                "Bytecode format version {} is not supported, expected version {}",
+                version, FORMAT_VERSION
+            ));
+        }
+
+        let mut ret_val = Bytecode {
+            buffer: None,
+            stack: None,
+            scratch: None,
+            raw_text: reader.string()?.to_string(),
+            instructions: vec![],
+            info: vec![],
+            max_stack: 0,
+            saved_nums: 0,
+            saved_texts: 0,
+            constants: vec![],
+            folded_filters: vec![],
+        };
+
+        let count = reader.uint()?;
+        for _ in 0..count {
+            let name = reader.string()?;
+            let saved = reader.constant()?;
+            match constant(name, env) {
+                Some(ref current) if *current == saved => {}
+                Some(current) => {
+                    return Err(format!(
+                        "The constant {:?} was {} when the bytecode was compiled, but this environment has {}",
+                        name, saved, current
+                    ))
+                }
+                None => {
+                    return Err(format!(
+                        "The constant {:?} was compiled into the bytecode, but this environment doesn't have it",
+                        name
+                    ))
+                }
+            }
+            ret_val.constants.push((name.to_string(), saved));
+        }
+
+        let count = reader.uint()?;
+        for _ in 0..count {
+            let filter = FoldedFilter {
+                name: reader.string()?.to_string(),
+                args: reader.args()?,
+                input: reader.constant()?,
+                output: reader.constant()?,
+            };
+            match run_filter(&filter, env) {
+                Some(ref current) if *current == filter.output => {}
+                Some(current) => {
+                    return Err(format!(
+                        "The pure filter {} gave {} for {} when the bytecode was compiled, but this environment gives {}",
+                        filter.name, filter.output, filter.input, current
+                    ))
+                }
+                None => {
+                    return Err(format!(
+                        "The pure filter {} was run on {} when the bytecode was compiled, but this environment can't run it",
+                        filter.name, filter.input
+                    ))
+                }
+            }
+            ret_val.folded_filters.push(filter);
+        }
+
+        let count = reader.uint()?;
+        for _ in 0..count {
+            let (instr, mut info) = match reader.byte()? {
+                PRINT_RAW => {
+                    let start = reader.usize()?;
+                    let end = reader.usize()?;
+                    if start > end
+                        || end > ret_val.raw_text.len()
+                        || !ret_val.raw_text.is_char_boundary(start)
+                        || !ret_val.raw_text.is_char_boundary(end)
+                    {
+                        return Err(format!("Invalid raw text range {}..{}", start, end));
+                    }
+                    (Instr::PrintRaw(start, end), InstrInfo::default())
+                }
+                PRINT_STR => {
+                    let var = reader.string()?;
+                    (
+                        Instr::PrintStr(str_var::<_, _, _, Env>(var)?),
+                        var_info(var),
+                    )
+                }
+                PRINT_NUM => {
+                    let var = reader.string()?;
+                    (
+                        Instr::PrintNum(num_var::<_, _, _, Env>(var)?),
+                        var_info(var),
+                    )
+                }
+                PRINT_REG => (Instr::PrintReg, InstrInfo::default()),
+                PUSH_IMM => (Instr::PushImm(reader.num()?), InstrInfo::default()),
+                PUSH_NUM => {
+                    let var = reader.string()?;
+                    (Instr::PushNum(num_var::<_, _, _, Env>(var)?), var_info(var))
+                }
+                op @ CALL_REG
+                | op @ CALL_ID
+                | op @ CALL_STR
+                | op @ CALL_REG_STR
+                | op @ SAVE_CALL => {
+                    let filter = reader.string()?;
+                    let args = reader.args()?;
+                    let (val, arg_count, input_type, pure) = Env::filter(filter)
+                        .ok_or_else(|| format!("Unknown filter named {}", filter))?;
+                    if arg_count != args.len() {
+                        return Err(format!(
+                            "filter {} expected {} args, but {} were provided",
+                            filter,
+                            arg_count,
+                            args.len()
+                        ));
+                    }
+
+                    let mut info = InstrInfo {
+                        filter: Some(filter.to_string()),
+                        ..InstrInfo::default()
+                    };
+                    let instr = match (op, input_type) {
+                        (CALL_REG, FilterInput::Numeric) => Instr::CallReg(val, args),
+                        (CALL_REG_STR, FilterInput::Stringified) => Instr::CallRegStr(val, args),
+                        (SAVE_CALL, FilterInput::Numeric) if pure => {
+                            Instr::SaveCall(val, args, reader.slot()?)
+                        }
+                        (SAVE_CALL, FilterInput::Numeric) => {
+                            return Err(format!(
+                                "filter {} was pure when the bytecode was saved, but isn't any more",
+                                filter
+                            ))
+                        }
+                        (CALL_STR, FilterInput::Stringified) => {
+                            let var = reader.string()?;
+                            info.var = Some(var.to_string());
+                            Instr::CallStr(val, args, str_var::<_, _, _, Env>(var)?)
+                        }
+                        (CALL_ID, FilterInput::StrEnumId(valid_ids)) => {
+                            let var = reader.string()?;
+                            let val_id = str_var::<_, _, _, Env>(var)?;
+                            if !valid_ids.contains(&val_id) {
+                                return Err(format!(
+                                    "filter {} expected one of these identifiers: {:#?}.\nErroneous identifier found: {:#?}",
+                                    filter, valid_ids, val_id
+                                ));
+                            }
+                            info.var = Some(var.to_string());
+                            Instr::CallId(val, args, val_id)
+                        }
+                        _ => {
+                            return Err(format!(
+                                "filter {} takes a different type of input than it did when the bytecode was saved",
+                                filter
+                            ))
+                        }
+                    };
+                    (instr, info)
+                }
+                ADD => (Instr::Add, InstrInfo::default()),
+                SUB => (Instr::Sub, InstrInfo::default()),
+                MUL => (Instr::Mul, InstrInfo::default()),
+                DIV => (Instr::Div, InstrInfo::default()),
+                ADD_IMM => (Instr::AddImm(reader.num()?), InstrInfo::default()),
+                SUB_IMM => (Instr::SubImm(reader.num()?), InstrInfo::default()),
+                MUL_IMM => (Instr::MulImm(reader.num()?), InstrInfo::default()),
+                DIV_IMM => (Instr::DivImm(reader.num()?), InstrInfo::default()),
+                NEG => (Instr::Neg, InstrInfo::default()),
+                SAVE_NUM => {
+                    let var = reader.string()?;
+                    let id = num_var::<_, _, _, Env>(var)?;
+                    (Instr::SaveNum(id, reader.slot()?), var_info(var))
+                }
+                LOAD_NUM => (Instr::LoadNum(reader.slot()?), InstrInfo::default()),
+                SAVE_STR => {
+                    let var = reader.string()?;
+                    let id = str_var::<_, _, _, Env>(var)?;
+                    (Instr::SaveStr(id, reader.slot()?), var_info(var))
+                }
+                SAVE_OUTPUT => (Instr::SaveOutput(reader.slot()?), InstrInfo::default()),
+                PRINT_SAVED => (Instr::PrintSaved(reader.slot()?), InstrInfo::default()),
+                PRINT_SAVED_NUM => (Instr::PrintSavedNum(reader.slot()?), InstrInfo::default()),
+                op => return Err(format!("Unknown opcode {}", op)),
+            };
+            info.span = Span {
+                start: reader.usize()?,
+                end: reader.usize()?,
+            };
+
+            ret_val.instructions.push(instr);
+            ret_val.info.push(info);
+        }
+
+        // make sure the render loop can never underflow the stack, or read a saved value that isn't there
+        ret_val.max_stack = ret_val.stack_depth()?;
+        let (saved_nums, saved_texts) = ret_val.saved_slots()?;
+        ret_val.saved_nums = saved_nums;
+        ret_val.saved_texts = saved_texts;
+        if !reader.bytes.is_empty() {
+            return Err("Unexpected trailing data after the bytecode".to_string());
+        }
+
+        Ok(ret_val)
+    }
+}
+
+fn var_info(var: &str) -> InstrInfo {
+    InstrInfo {
+        var: Some(var.to_string()),
+        ..InstrInfo::default()
+    }
+}
+
+fn num_var<'a, NumEnum, StrEnum, FilterEnum, Env>(name: &str) -> Result<NumEnum, String>
+where
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+{
+    Env::num_var(name).ok_or_else(|| format!("Unknown numeric identifier {:?}", name))
+}
+
+fn str_var<'a, NumEnum, StrEnum, FilterEnum, Env>(name: &str) -> Result<StrEnum, String>
+where
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+{
+    Env::str_var(name).ok_or_else(|| format!("Unknown string identifier {:?}", name))
+}
+
+fn write_uint(out: &mut Vec<u8>, mut val: u64) {
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_num(out: &mut Vec<u8>, val: f64) {
+    out.extend_from_slice(&val.to_bits().to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, string: &str) {
+    write_uint(out, string.len() as u64);
+    out.extend_from_slice(string.as_bytes());
+}
+
+fn write_constant(out: &mut Vec<u8>, constant: &Constant) {
+    match *constant {
+        Constant::Num(val) => {
+            out.push(CONSTANT_NUM);
+            write_num(out, val);
+        }
+        Constant::Str(ref val) => {
+            out.push(CONSTANT_STR);
+            write_str(out, val);
+        }
+    }
+}
+
+fn write_args(out: &mut Vec<u8>, args: &[f64]) {
+    write_uint(out, args.len() as u64);
+    for &arg in args {
+        write_num(out, arg);
+    }
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, len: usize) -> Result<&'b [u8], String> {
+        if len > self.bytes.len() {
+            return Err("Unexpected end of bytecode".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uint(&mut self) -> Result<u64, String> {
+        let mut val = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            // the tenth byte only has room for the top bit
+            if shift == 63 && bits > 1 {
+                break;
+            }
+            val |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+        Err("Malformed integer in bytecode".to_string())
+    }
+
+    // a length or position, which has to fit in memory on this platform
+    fn usize(&mut self) -> Result<usize, String> {
+        let val = self.uint()?;
+        usize::try_from(val).map_err(|_| format!("{} is too large for this platform", val))
+    }
+
+    fn num(&mut self) -> Result<f64, String> {
+        let mut bits = [0u8; 8];
+        bits.copy_from_slice(self.take(8)?);
+        Ok(f64::from_bits(u64::from_le_bytes(bits)))
+    }
+
+    // slots are bounded so a corrupt file can't make rendering allocate an enormous stack
+    fn slot(&mut self) -> Result<usize, String> {
+        let slot = self.usize()?;
+        if slot > 1 << 20 {
+            return Err(format!("Saved value slot {} is out of range", slot));
+        }
+        Ok(slot)
+    }
+
+    fn string(&mut self) -> Result<&'b str, String> {
+        let len = self.usize()?;
+        ::std::str::from_utf8(self.take(len)?).map_err(|_| "Invalid UTF-8 in bytecode".to_string())
+    }
+
+    fn constant(&mut self) -> Result<Constant, String> {
+        match self.byte()? {
+            CONSTANT_NUM => Ok(Constant::Num(self.num()?)),
+            CONSTANT_STR => Ok(Constant::Str(self.string()?.to_string())),
+            kind => Err(format!("Unknown constant type {}", kind)),
+        }
+    }
+
+    fn args(&mut self) -> Result<Vec<f64>, String> {
+        let len = self.usize()?;
+        let mut args = Vec::with_capacity(len.min(self.bytes.len() / 8));
+        for _ in 0..len {
+            args.push(self.num()?);
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FORMAT_VERSION;
+    use bytecode::Bytecode;
+    use compile;
+    use test_support::*;
+
+    const TEMPLATE: &str =
+        "{{provider}} {{provider_code + 4}} {{id}} {{name | toupper}} {{age | sqrt}} {{weight / 2.2 | round 2}}kg\n";
+
+    #[test]
+    fn round_trip() {
+        let env = provider();
+        let mut bytecode = compile(TEMPLATE, &env).unwrap();
+        let bytes = bytecode.to_bytes();
+
+        let mut loaded = Bytecode::from_bytes(&bytes, &env).unwrap();
+        assert_eq!(loaded.to_bytes(), bytes);
+        assert_eq!(
+            loaded.render_to_string(&person()),
+            bytecode.render_to_string(&person())
+        );
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let env = provider();
+        let mut bytes = compile(TEMPLATE, &env).unwrap().to_bytes();
+        bytes[4] = FORMAT_VERSION as u8 + 1;
+
+        let err = Bytecode::from_bytes(&bytes, &env).unwrap_err();
+        assert!(err.contains("version"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        let env = provider();
+        let bytes = compile("{{name}}", &env).unwrap().to_bytes();
+        let pos = bytes.windows(4).position(|w| w == b"name").unwrap();
+        let mut renamed = bytes.clone();
+        renamed[pos + 1] = b'o';
+
+        let err = Bytecode::from_bytes(&renamed, &env).unwrap_err();
+        assert!(err.contains("nome"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_changed_constants() {
+        let env = provider();
+        let bytes = compile(TEMPLATE, &env).unwrap().to_bytes();
+
+        let mut renumbered = provider();
+        renumbered.provider_code = 32;
+        let err = Bytecode::from_bytes(&bytes, &renumbered).unwrap_err();
+        assert_eq!(
+            err,
+            r#"The constant "provider_code" was 31 when the bytecode was compiled, but this environment has 32"#
+        );
+
+        let mut renamed = provider();
+        renamed.provider = "jane doe".to_string();
+        let err = Bytecode::from_bytes(&bytes, &renamed).unwrap_err();
+        assert_eq!(
+            err,
+            r#"The constant "provider" was "john doe" when the bytecode was compiled, but this environment has "jane doe""#
+        );
+    }
+
+    #[test]
+    fn rejects_changed_filter_output() {
+        let env = provider();
+        let bytes = compile("{{provider | toupper}} {{provider_code | sqrt}}", &env)
+            .unwrap()
+            .to_bytes();
+        assert!(Bytecode::from_bytes(&bytes, &env).is_ok());
+
+        // the raw text comes first, so the last copy is the output stored with the call
+        let pos = bytes.windows(8).rposition(|w| w == b"JOHN DOE").unwrap();
+        let mut changed = bytes.clone();
+        changed[pos + 7] = b'F';
+        let err = Bytecode::from_bytes(&changed, &env).unwrap_err();
+        assert_eq!(
+            err,
+            r#"The pure filter toupper gave "JOHN DOF" for "john doe" when the bytecode was compiled, but this environment gives "JOHN DOE""#
+        );
+
+        let pos = bytes.windows(7).position(|w| w == b"toupper").unwrap();
+        let mut renamed = bytes.clone();
+        renamed[pos + 6] = b'd';
+        let err = Bytecode::from_bytes(&renamed, &env).unwrap_err();
+        assert_eq!(
+            err,
+            r#"The pure filter toupped was run on "john doe" when the bytecode was compiled, but this environment can't run it"#
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_integers() {
+        let env = provider();
+        // a version number with bits past the end of a u64
+        let mut bytes = b"ZAPR".to_vec();
+        bytes.extend_from_slice(&[0xff; 9]);
+        bytes.push(0x02);
+        let err = Bytecode::from_bytes(&bytes, &env).unwrap_err();
+        assert_eq!(err, "Malformed integer in bytecode");
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let env = provider();
+        let bytes = compile(TEMPLATE, &env).unwrap().to_bytes();
+        for len in 0..bytes.len() {
+            assert!(Bytecode::from_bytes(&bytes[..len], &env).is_err());
+        }
+    }
+}
@@ -0,0 +1,241 @@
+// partial evaluation of compiled bytecode. Variables which hold the same value for a whole batch of items are swapped
+// for that value, and everything this makes constant is folded away: arithmetic on known values is computed, known
+// values are printed into the static text, and neighbouring static text is merged back together.
+
+use super::{Bytecode, Instr, InstrInfo};
+use number::NumBuffer;
+use std::collections::HashMap;
+
+enum Folded<NumEnum, StrEnum, FilterEnum> {
+    Text(String),
+    Instr(Instr<NumEnum, StrEnum, FilterEnum>),
+}
+
+struct Folder<NumEnum, StrEnum, FilterEnum> {
+    items: Vec<(Folded<NumEnum, StrEnum, FilterEnum>, InstrInfo)>,
+}
+
+impl<NumEnum, StrEnum, FilterEnum> Folder<NumEnum, StrEnum, FilterEnum> {
+    // the value pushed by the item `depth` places from the end, if it is a constant
+    fn constant(&self, depth: usize) -> Option<f64> {
+        if depth > self.items.len() {
+            return None;
+        }
+        match self.items[self.items.len() - depth].0 {
+            Folded::Instr(Instr::PushImm(val)) => Some(val),
+            _ => None,
+        }
+    }
+
+    // drops the last `count` items, returning `info` widened to cover them
+    fn pop(&mut self, count: usize, mut info: InstrInfo) -> InstrInfo {
+        let start = self.items.len() - count;
+        for (_, popped) in self.items.drain(start..) {
+            info.span = popped.span.to(info.span);
+        }
+        info
+    }
+
+    fn text(&mut self, text: &str, info: InstrInfo) {
+        if let Some(&mut (Folded::Text(ref mut prev), ref mut prev_info)) = self.items.last_mut() {
+            prev.push_str(text);
+            prev_info.span = prev_info.span.to(info.span);
+            return;
+        }
+        self.items.push((Folded::Text(text.to_string()), info));
+    }
+
+    fn instr(&mut self, instr: Instr<NumEnum, StrEnum, FilterEnum>, info: InstrInfo) {
+        // the operations happen in the same order the VM would do them, so the results are exactly the same
+        let (count, folded) = match (self.constant(2), self.constant(1), &instr) {
+            (Some(left), Some(right), &Instr::Add) => (2, left + right),
+            (Some(left), Some(right), &Instr::Sub) => (2, left - right),
+            (Some(left), Some(right), &Instr::Mul) => (2, left * right),
+            (Some(left), Some(right), &Instr::Div) => (2, left / right),
+            (_, Some(left), &Instr::AddImm(right)) => (1, left + right),
+            (_, Some(left), &Instr::SubImm(right)) => (1, left - right),
+            (_, Some(left), &Instr::MulImm(right)) => (1, left * right),
+            (_, Some(left), &Instr::DivImm(right)) => (1, left / right),
+            (_, Some(val), &Instr::Neg) => (1, -val),
+            (_, Some(val), &Instr::PrintReg) => {
+                let info = self.pop(1, info);
+                self.text(NumBuffer::new().format(val), info);
+                return;
+            }
+            _ => {
+                self.items.push((Folded::Instr(instr), info));
+                return;
+            }
+        };
+        let info = self.pop(count, info);
+        self.items
+            .push((Folded::Instr(Instr::PushImm(folded)), info));
+    }
+}
+
+impl<NumEnum, StrEnum, FilterEnum> Bytecode<NumEnum, StrEnum, FilterEnum>
+where
+    NumEnum: Copy + PartialEq,
+    StrEnum: Copy + PartialEq,
+    FilterEnum: Copy,
+{
+    /// Builds a copy of this bytecode with some of the runner's variables fixed to known values, for rendering a
+    /// batch of items which all share them. The known values are folded into the static text along with anything
+    /// computed from them, so the result does less work per item than the original.
+    ///
+    /// Filters are still called for every item, since only the runner can run them, and filters which take a
+    /// string variable as their input still read it from the runner.
+    pub fn specialize(
+        &self,
+        nums: &[(NumEnum, f64)],
+        strs: &[(StrEnum, &str)],
+    ) -> Bytecode<NumEnum, StrEnum, FilterEnum> {
+        let num = |id| {
+            nums.iter()
+                .find(|&&(var, _)| var == id)
+                .map(|&(_, val)| val)
+        };
+        let string = |id| {
+            strs.iter()
+                .find(|&&(var, _)| var == id)
+                .map(|&(_, val)| val)
+        };
+
+        // memoised values which turned out to be known, by the slot they would have been saved in
+        let mut saved_nums = HashMap::new();
+        let mut saved_strs = HashMap::new();
+
+        let mut folder = Folder { items: vec![] };
+        for (instr, info) in self.instructions.iter().zip(&self.info) {
+            let info = info.clone();
+            match *instr {
+                Instr::PrintRaw(start, end) => folder.text(&self.raw_text[start..end], info),
+                Instr::PrintStr(id) => match string(id) {
+                    Some(val) => folder.text(val, info),
+                    None => folder.instr(Instr::PrintStr(id), info),
+                },
+                Instr::PrintNum(id) => match num(id) {
+                    Some(val) => folder.text(NumBuffer::new().format(val), info),
+                    None => folder.instr(Instr::PrintNum(id), info),
+                },
+                Instr::PushNum(id) => match num(id) {
+                    Some(val) => folder.instr(Instr::PushImm(val), info),
+                    None => folder.instr(Instr::PushNum(id), info),
+                },
+                Instr::SaveNum(id, slot) => match num(id) {
+                    Some(val) => {
+                        saved_nums.insert(slot, val);
+                        folder.instr(Instr::PushImm(val), info)
+                    }
+                    None => folder.instr(Instr::SaveNum(id, slot), info),
+                },
+                Instr::LoadNum(slot) => match saved_nums.get(&slot) {
+                    Some(&val) => folder.instr(Instr::PushImm(val), info),
+                    None => folder.instr(Instr::LoadNum(slot), info),
+                },
+                Instr::SaveStr(id, slot) => match string(id) {
+                    Some(val) => {
+                        saved_strs.insert(slot, val);
+                        folder.text(val, info)
+                    }
+                    None => folder.instr(Instr::SaveStr(id, slot), info),
+                },
+                Instr::PrintSaved(slot) => match saved_strs.get(&slot) {
+                    Some(val) => folder.text(val, info),
+                    None => folder.instr(Instr::PrintSaved(slot), info),
+                },
+                ref instr => folder.instr(instr.clone(), info),
+            }
+        }
+
+        let mut bytecode = Bytecode {
+            buffer: None,
+            stack: None,
+            scratch: None,
+            raw_text: String::new(),
+            instructions: vec![],
+            info: vec![],
+            max_stack: 0,
+            saved_nums: self.saved_nums,
+            saved_texts: self.saved_texts,
+            constants: self.constants.clone(),
+            folded_filters: self.folded_filters.clone(),
+        };
+        for (item, info) in folder.items {
+            let instr = match item {
+                Folded::Text(text) => {
+                    let start = bytecode.raw_text.len();
+                    bytecode.raw_text.push_str(&text);
+                    Instr::PrintRaw(start, bytecode.raw_text.len())
+                }
+                Folded::Instr(instr) => instr,
+            };
+            bytecode.instructions.push(instr);
+            bytecode.info.push(info);
+        }
+
+        // folding can leave constants next to the operations that use them, so fuse those again. This also works out
+        // the new stack depth.
+        bytecode.peephole();
+        bytecode
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use compile;
+    use test_support::*;
+
+    #[test]
+    fn folds_known_values() {
+        let env = provider();
+        let template =
+            "{{provider}} {{id}} {{name | toupper}} {{name}} {{age * 2 + 1}} {{age - id | sqrt}} \
+                        {{-age}}";
+        let mut bytecode = compile(template, &env).unwrap();
+        let mut specialized =
+            bytecode.specialize(&[(PersonNums::Age, 49.0)], &[(PersonStrs::Name, "Bob")]);
+
+        let listing = specialized
+            .disassemble()
+            .into_iter()
+            .map(|instr| instr.instr)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listing,
+            [
+                r#"PrintRaw "john doe ""#,
+                "SaveNum id #0",
+                "PrintReg",
+                r#"PrintRaw " ""#,
+                "CallStr toupper() name",
+                r#"PrintRaw " Bob 99 ""#,
+                "PushImm 49",
+                "LoadNum #0",
+                "Sub",
+                "CallReg sqrt()",
+                r#"PrintRaw " -49""#,
+            ]
+        );
+        assert_eq!(specialized.max_stack_depth(), 2);
+
+        let person = person();
+        assert_eq!(
+            specialized.render_to_string(&person),
+            bytecode.render_to_string(&person)
+        );
+    }
+
+    #[test]
+    fn nothing_known() {
+        let env = provider();
+        let template = "{{provider}} {{age / 2 | round 1}} {{weight}}kg";
+        let mut bytecode = compile(template, &env).unwrap();
+        let mut specialized = bytecode.specialize(&[], &[]);
+        assert_eq!(specialized.disassemble(), bytecode.disassemble());
+        assert_eq!(
+            specialized.render_to_string(&person()),
+            bytecode.render_to_string(&person())
+        );
+    }
+}
@@ -0,0 +1,272 @@
+// template inheritance, with `{{extends "base"}}` and `{{#block name}}...{{/block}}`. A child template only
+// contributes its blocks: it renders as its base template does, except that each block the child defines replaces
+// the base's block of the same name. Base templates can extend other templates in turn, and the most derived
+// version of each block wins. All of this is resolved into a single list of expressions before the template is
+// optimized, so the Bytecode it compiles to never knows that blocks existed.
+
+use ast::{self, Expr, Span};
+use partials::{IncludeSite, Partials, SpannedAst};
+use std::collections::HashMap;
+use std::mem;
+use tokenizer::{Syntax, Tokenizer};
+
+#[derive(Clone, Debug)]
+enum Node<'a> {
+    Expr(Expr<'a>, Span),
+    Block(&'a str, Vec<Node<'a>>),
+}
+
+type Blocks<'a> = HashMap<&'a str, Vec<Node<'a>>>;
+
+/// Resolves the blocks in a template, along with the base templates it extends, which are looked up in
+/// `templates`. Everything that comes from a base template carries the span of the `{{extends}}`, and the
+/// returned `IncludeSite` describes that for errors to point at. Base templates are tokenized with the same
+/// `syntax` as the template.
+pub fn resolve<'a>(
+    ast: SpannedAst<'a>,
+    templates: &'a dyn Partials,
+    syntax: &Syntax,
+) -> Result<(SpannedAst<'a>, Option<IncludeSite>), String> {
+    let mut nodes = nest(ast)?;
+    let (mut base, span) = match extends(&nodes) {
+        Some(extends) => extends,
+        None => {
+            let mut resolved = Vec::new();
+            flatten(nodes, &HashMap::new(), &mut vec![], &mut resolved)?;
+            return Ok((resolved, None));
+        }
+    };
+
+    // anything in a child template outside of its blocks is ignored
+    let mut blocks = HashMap::new();
+    collect_blocks(&nodes, &mut blocks)?;
+
+    let mut site = IncludeSite {
+        span,
+        chain: vec![base.to_string()],
+        extends: true,
+    };
+    loop {
+        let source = match templates.partial(base) {
+            Some(source) => source,
+            None => return Err(site.describe("no template with that name exists")),
+        };
+        let ast = ast::parse_spanned(Tokenizer::with_syntax(source, syntax.clone()))
+            .map_err(|err| site.describe(&err))?;
+        let ast = ast.into_iter().map(|(tree, _)| (tree, span)).collect();
+        nodes = nest(ast).map_err(|err| site.describe(&err))?;
+
+        let next = match extends(&nodes) {
+            Some((next, _)) => next,
+            None => break,
+        };
+        if site.chain.iter().any(|extended| extended == next) {
+            let cycle = format!("extending {:?} again would never end", next);
+            return Err(site.describe(&cycle));
+        }
+
+        // the blocks in a more derived template take priority over these ones
+        let mut own = HashMap::new();
+        collect_blocks(&nodes, &mut own).map_err(|err| site.describe(&err))?;
+        for (name, body) in own {
+            blocks.entry(name).or_insert(body);
+        }
+        site.chain.push(next.to_string());
+        base = next;
+    }
+
+    let mut resolved = Vec::new();
+    flatten(nodes, &blocks, &mut vec![], &mut resolved).map_err(|err| site.describe(&err))?;
+    Ok((resolved, Some(site)))
+}
+
+// builds the blocks in a flat list of expressions into a tree
+fn nest(ast: SpannedAst) -> Result<Vec<Node>, String> {
+    // the blocks that are still open, each along with everything that came before it
+    let mut open = Vec::new();
+    let mut nodes = Vec::new();
+    for (tree, span) in ast {
+        match tree {
+            Expr::Block(name) => open.push((name, span, mem::take(&mut nodes))),
+            Expr::EndBlock => match open.pop() {
+                Some((name, _, outer)) => {
+                    let body = mem::replace(&mut nodes, outer);
+                    nodes.push(Node::Block(name, body));
+                }
+                None => {
+                    return Err(format!(
+                        "{{{{/block}}}} at template {}..{} doesn't close any block",
+                        span.start, span.end
+                    ))
+                }
+            },
+            tree => nodes.push(Node::Expr(tree, span)),
+        }
+    }
+
+    match open.pop() {
+        Some((name, span, _)) => Err(format!(
+            "The block {:?} opened at template {}..{} is never closed",
+            name, span.start, span.end
+        )),
+        None => Ok(nodes),
+    }
+}
+
+// the template this one extends, which has to come before anything but whitespace
+fn extends<'a>(nodes: &[Node<'a>]) -> Option<(&'a str, Span)> {
+    let first = nodes.iter().find(|node| match **node {
+        Node::Expr(Expr::Raw(text), _) => !text.trim().is_empty(),
+        _ => true,
+    });
+    match first {
+        Some(&Node::Expr(Expr::Extends(name), span)) => Some((name, span)),
+        _ => None,
+    }
+}
+
+fn collect_blocks<'a>(nodes: &[Node<'a>], blocks: &mut Blocks<'a>) -> Result<(), String> {
+    for node in nodes {
+        if let Node::Block(name, ref body) = *node {
+            collect_blocks(body, blocks)?;
+            if blocks.insert(name, body.clone()).is_some() {
+                return Err(format!("The block {:?} is defined more than once", name));
+            }
+        }
+    }
+    Ok(())
+}
+
+// replaces each block with its most derived version, and flattens the tree back into a list of expressions
+fn flatten<'a>(
+    nodes: Vec<Node<'a>>,
+    blocks: &Blocks<'a>,
+    open: &mut Vec<&'a str>,
+    resolved: &mut SpannedAst<'a>,
+) -> Result<(), String> {
+    for node in nodes {
+        match node {
+            Node::Expr(Expr::Extends(name), span) => {
+                return Err(format!(
+                    "The extends of {:?} at template {}..{} has to come before everything else",
+                    name, span.start, span.end
+                ))
+            }
+            Node::Expr(tree, span) => resolved.push((tree, span)),
+            Node::Block(name, body) => {
+                // overrides from different templates can end up nesting a block inside of itself
+                if open.contains(&name) {
+                    return Err(format!("The block {:?} ends up inside of itself", name));
+                }
+                let body = blocks.get(name).cloned().unwrap_or(body);
+                open.push(name);
+                flatten(body, blocks, open, resolved)?;
+                open.pop();
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use compile_with_partials;
+    use std::collections::HashMap;
+    use test_support::*;
+    use CompileOptions;
+
+    fn render(
+        template: &str,
+        templates: &[(&'static str, &'static str)],
+    ) -> Result<String, String> {
+        let env = provider();
+        let templates = templates.iter().cloned().collect::<HashMap<_, _>>();
+        let mut bytecode =
+            compile_with_partials(template, &env, &templates, &CompileOptions::default())?;
+        Ok(bytecode.render_to_string(&person()))
+    }
+
+    const BASE: &str = "<title>{{#block title}}{{provider}}{{/block}}</title>\n\
+                        {{#block body}}<p>{{#block greeting}}hello{{/block}}</p>{{/block}}\n\
+                        {{#block footer}}-- {{id}}{{/block}}";
+
+    #[test]
+    fn overrides_blocks() {
+        let templates = [
+            ("base", BASE),
+            (
+                "email",
+                r#"{{extends "base"}}{{#block greeting}}dear {{name}}{{/block}}"#,
+            ),
+        ];
+        assert_eq!(
+            render("{{#block a}}{{age}}{{/block}} standalone", &templates).unwrap(),
+            "49 standalone"
+        );
+        assert_eq!(
+            render(r#"{{extends "base"}}"#, &templates).unwrap(),
+            "<title>john doe</title>\n<p>hello</p>\n-- 12"
+        );
+        assert_eq!(
+            render(
+                r#"
+                {{extends "email"}}
+                ignored
+                {{#block title}}Hi {{name | toupper}}{{/block}}
+                {{#block footer}}{{/block}}
+                "#,
+                &templates
+            )
+            .unwrap(),
+            "<title>Hi BOB</title>\n<p>dear Bob</p>\n"
+        );
+        assert_eq!(
+            render(
+                r#"{{extends "email"}}{{#block body}}{{#block greeting}}yo{{/block}}!{{/block}}"#,
+                &templates
+            )
+            .unwrap(),
+            "<title>john doe</title>\nyo!\n-- 12"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let templates = [
+            ("base", BASE),
+            ("a", r#"{{extends "b"}}"#),
+            ("b", r#"{{extends "a"}}"#),
+            ("typo", "{{#block x}}{{nmae}}{{/block}}"),
+        ];
+        let error = |template| render(template, &templates).unwrap_err();
+
+        assert_eq!(
+            error(r#"{{extends "a"}}"#),
+            r#"in base template a > b extended at template 0..15: extending "a" again would never end"#
+        );
+        assert_eq!(
+            error(r#"{{extends "missing"}}"#),
+            "in base template missing extended at template 0..21: no template with that name exists"
+        );
+        assert_eq!(
+            error(r#"{{extends "typo"}}"#),
+            r#"in base template typo extended at template 0..18: Unknown identifier "nmae""#
+        );
+        assert_eq!(
+            error(r#"{{extends "base"}}{{#block x}}{{/block}}{{#block x}}{{/block}}"#),
+            r#"The block "x" is defined more than once"#
+        );
+        assert_eq!(
+            error("{{#block x}}{{/block}}{{/block}}"),
+            "{{/block}} at template 22..32 doesn't close any block"
+        );
+        assert_eq!(
+            error("{{#block x}}"),
+            r#"The block "x" opened at template 0..12 is never closed"#
+        );
+        assert_eq!(
+            error(r#"hi {{extends "base"}}"#),
+            r#"The extends of "base" at template 3..21 has to come before everything else"#
+        );
+    }
+}
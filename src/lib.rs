@@ -5,41 +5,40 @@ extern crate zapper_derive;
 #[cfg(feature = "derive")]
 pub use zapper_derive::*;
 
+extern crate itoa;
 #[cfg(feature = "rayon")]
 extern crate rayon;
+extern crate ryu;
+extern crate zapper_front;
+
+#[cfg(feature = "async")]
+extern crate futures_io;
 
-pub mod ast;
 pub mod bytecode;
-pub mod optimizer;
-pub mod tokenizer;
+mod inheritance;
+pub mod loader;
+mod number;
+mod options;
+pub mod partials;
+pub mod registry;
+#[cfg(feature = "async")]
+pub mod render_async;
+mod vectored;
+
+#[cfg(test)]
+mod test_support;
 
 use std::borrow::Cow;
 use std::fmt::Debug;
 
-pub use bytecode::Bytecode;
-
-pub enum FilterInput<StrEnum> {
-    Numeric,
-    StrEnumId(Vec<StrEnum>),
-    Stringified,
-}
-
-pub trait Environment<
-    'a,
-    NumEnum: 'a + Send + Sync,
-    StrEnum: 'a + Send + Sync + Debug + PartialEq,
-    FilterEnum: 'a + Send + Sync,
->
-{
-    fn num_constant(&self, &str) -> Option<f64>;
-    fn str_constant(&'a self, &str) -> Option<Cow<'a, str>>;
-
-    fn num_var(&str) -> Option<NumEnum>;
-    fn str_var(&str) -> Option<StrEnum>;
-
-    // returns a FilterEnum, the number of arguments, and the input data type
-    fn filter(&str) -> Option<(FilterEnum, usize, FilterInput<StrEnum>)>;
-}
+pub use bytecode::{Bytecode, ClosureProgram, DisassembledInstr, RenderCursor};
+pub use loader::DirectoryLoader;
+pub use options::CompileOptions;
+pub use partials::{NoPartials, Partials};
+pub use registry::Registry;
+#[cfg(feature = "async")]
+pub use render_async::RenderAsync;
+pub use zapper_front::{ast, optimizer, tokenizer, Environment, FilterInput};
 
 #[allow(unused)]
 pub trait Runner<NumEnum: Send + Sync, StrEnum: Send + Sync, FilterEnum: Send + Sync> {
@@ -63,10 +62,73 @@ pub fn compile<
     source: &'a str,
     environment: &'a Env,
 ) -> Result<Bytecode<NumEnum, StrEnum, FilterEnum>, String> {
-    let tokenizer = tokenizer::Tokenizer::new(source);
-    let ast = ast::parse(tokenizer)?;
+    compile_with(source, environment, &CompileOptions::default())
+}
+
+/// Compiles a template like `compile`, with control over the optimizations that run along the way.
+pub fn compile_with<
+    'a,
+    NumEnum: 'a + Send + Sync + Copy + Debug,
+    StrEnum: 'a + Send + Sync + Copy + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync + Copy + Debug,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+>(
+    source: &'a str,
+    environment: &'a Env,
+    options: &CompileOptions,
+) -> Result<Bytecode<NumEnum, StrEnum, FilterEnum>, String> {
+    compile_with_partials(source, environment, &NoPartials, options)
+}
+
+/// Compiles a template like `compile_with`, resolving any `{{> name}}` includes and `{{extends "name"}}` base
+/// templates from `partials`. Everything is inlined into the resulting Bytecode, so rendering it never needs to look
+/// anything up.
+pub fn compile_with_partials<
+    'a,
+    NumEnum: 'a + Send + Sync + Copy + Debug,
+    StrEnum: 'a + Send + Sync + Copy + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync + Copy + Debug,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+>(
+    source: &'a str,
+    environment: &'a Env,
+    partials: &'a dyn Partials,
+    options: &CompileOptions,
+) -> Result<Bytecode<NumEnum, StrEnum, FilterEnum>, String> {
+    let tokenizer = tokenizer::Tokenizer::with_syntax(source, options.syntax.clone());
+    let ast = ast::parse_spanned(tokenizer)?;
+    let (ast, base) = inheritance::resolve(ast, partials, &options.syntax)?;
+    let (ast, sites) = partials::expand(ast, partials, &options.syntax)?;
     // println!("ast: {:#?}\n", ast);
-    let ast = optimizer::optimize(ast, environment);
+    let passes: &[optimizer::Pass] = if options.optimize {
+        &options.passes
+    } else {
+        &[]
+    };
+    // remembered so that a saved copy of the bytecode can't be loaded against different constants
+    let constants = bytecode::folded_constants(&ast, environment);
+    let folding = passes
+        .iter()
+        .any(|pass| matches!(*pass, optimizer::Pass::ConstantFolding));
+    let filters = if folding {
+        bytecode::folded_filters(&ast, environment, options.effort)
+    } else {
+        vec![]
+    };
+    let ast = optimizer::run_passes(ast, environment, passes, options.effort);
     // println!("ast_opt: {:#?}\n", ast);
-    Bytecode::from_ast(ast, environment)
+    let mut bytecode = Bytecode::compile_spanned(ast, environment).map_err(|(err, span)| {
+        match sites.iter().chain(&base).find(|site| site.span == span) {
+            Some(site) => site.describe(&err),
+            None => err,
+        }
+    })?;
+    bytecode.record_constants(constants, filters);
+    if options.optimize && options.peephole {
+        bytecode.peephole();
+    }
+    if options.optimize && options.memoize {
+        bytecode.memoize::<Env>();
+    }
+    Ok(bytecode)
 }
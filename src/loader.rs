@@ -0,0 +1,225 @@
+// loads a directory of template files into a Registry, and keeps it up to date as they change. Each file is named by
+// its path relative to the directory, without the extension and with `/` between directories, so
+// `emails/welcome.html` becomes the template `emails/welcome`. Changes are found by polling the files' modification
+// times, either by calling `reload` or by having `watch` do it on a background thread.
+
+use registry::Registry;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use Environment;
+
+/// Keeps a Registry in sync with a directory of template files.
+pub struct DirectoryLoader {
+    root: PathBuf,
+    extension: Option<String>,
+    // the name, modification time and length of every file as of the last reload
+    files: HashMap<PathBuf, (String, SystemTime, u64)>,
+}
+
+impl DirectoryLoader {
+    pub fn new<P: AsRef<Path>>(root: P) -> DirectoryLoader {
+        DirectoryLoader {
+            root: root.as_ref().to_path_buf(),
+            extension: None,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Only loads files with this extension, such as `"html"`. By default every file is loaded, other than hidden
+    /// ones that start with a `.`
+    pub fn extension(mut self, extension: &str) -> DirectoryLoader {
+        self.extension = Some(extension.to_string());
+        self
+    }
+
+    /// Loads every file that is new or has changed since the last reload into the registry, and removes the
+    /// templates whose files are gone, returning the names of the templates that changed. Changed templates are
+    /// compiled straight away, so the error lists anything that failed to load or compile, one per line. A template
+    /// that fails to compile keeps rendering with the last Bytecode that did.
+    pub fn reload<NumEnum, StrEnum, FilterEnum, Env>(
+        &mut self,
+        registry: &Registry<NumEnum, StrEnum, FilterEnum, Env>,
+    ) -> Result<Vec<String>, String>
+    where
+        NumEnum: 'static + Send + Sync + Copy + Debug,
+        StrEnum: 'static + Send + Sync + Copy + Debug + PartialEq,
+        FilterEnum: 'static + Send + Sync + Copy + Debug,
+        Env: for<'a> Environment<'a, NumEnum, StrEnum, FilterEnum>,
+    {
+        let mut errors = Vec::new();
+        let mut found = Vec::new();
+        let root = self.root.clone();
+        if let Err(err) = self.scan(&root, &mut found) {
+            // without a full listing, every file that wasn't seen would look like it had been deleted
+            return Err(format!("could not read {}: {}", root.display(), err));
+        }
+
+        let mut changes = Vec::new();
+        for (path, name, modified, len) in found {
+            if let Some(&(_, last_modified, last_len)) = self.files.get(&path) {
+                if (last_modified, last_len) == (modified, len) {
+                    continue;
+                }
+            }
+            match fs::read_to_string(&path) {
+                Ok(source) => {
+                    self.files.insert(path, (name.clone(), modified, len));
+                    changes.push((name, Some(source)));
+                }
+                Err(err) => errors.push(format!("could not read {}: {}", path.display(), err)),
+            }
+        }
+
+        let gone = self
+            .files
+            .keys()
+            .filter(|path| !path.is_file())
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in gone {
+            let (name, _, _) = self.files.remove(&path).unwrap();
+            changes.push((name, None));
+        }
+
+        if let Err(err) = registry.update(&changes) {
+            errors.push(err);
+        }
+        if errors.is_empty() {
+            Ok(changes.into_iter().map(|(name, _)| name).collect())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// Reloads the directory every `interval` on a background thread, passing the result of each reload that found
+    /// any changes to `report`. The thread stops once it holds the only reference left to the registry.
+    pub fn watch<NumEnum, StrEnum, FilterEnum, Env, F>(
+        mut self,
+        registry: Arc<Registry<NumEnum, StrEnum, FilterEnum, Env>>,
+        interval: Duration,
+        mut report: F,
+    ) -> thread::JoinHandle<()>
+    where
+        NumEnum: 'static + Send + Sync + Copy + Debug,
+        StrEnum: 'static + Send + Sync + Copy + Debug + PartialEq,
+        FilterEnum: 'static + Send + Sync + Copy + Debug,
+        Env: 'static + Send + Sync + for<'a> Environment<'a, NumEnum, StrEnum, FilterEnum>,
+        F: 'static + Send + FnMut(Result<Vec<String>, String>),
+    {
+        thread::spawn(move || {
+            while Arc::strong_count(&registry) > 1 {
+                match self.reload(&registry) {
+                    Ok(ref changed) if changed.is_empty() => {}
+                    result => report(result),
+                }
+                thread::sleep(interval);
+            }
+        })
+    }
+
+    fn scan(
+        &self,
+        dir: &Path,
+        found: &mut Vec<(PathBuf, String, SystemTime, u64)>,
+    ) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            let metadata = fs::metadata(&path)?;
+            if metadata.is_dir() {
+                self.scan(&path, found)?;
+                continue;
+            }
+
+            let wanted = match self.extension {
+                Some(ref extension) => path.extension() == Some(OsStr::new(extension)),
+                None => true,
+            };
+            if let (true, Some(name)) = (wanted, self.name(&path)) {
+                found.push((path, name, metadata.modified()?, metadata.len()));
+            }
+        }
+        Ok(())
+    }
+
+    // the template name for a file, which is its path relative to the root with the extension removed
+    fn name(&self, path: &Path) -> Option<String> {
+        let path = path.strip_prefix(&self.root).ok()?.with_extension("");
+        let parts = path
+            .components()
+            .map(|part| part.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()?;
+        Some(parts.join("/"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use test_support::*;
+
+    fn render(
+        registry: &Registry<PersonNums, PersonStrs, PersonFilters, Provider>,
+        name: &str,
+    ) -> String {
+        let mut output = Vec::new();
+        registry.render(name, &person(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn reloads_changed_files() {
+        let root = env::temp_dir().join(format!("zapper-loader-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("emails")).unwrap();
+        fs::write(root.join("emails/header.html"), "<h1>{{name}}</h1>").unwrap();
+        fs::write(
+            root.join("emails/welcome.html"),
+            "{{> emails/header}}{{age}}",
+        )
+        .unwrap();
+        fs::write(root.join("notes.txt"), "not a template {{").unwrap();
+        fs::write(root.join(".welcome.html.swp"), "{{").unwrap();
+
+        let registry = Registry::new(provider());
+        let mut loader = DirectoryLoader::new(&root).extension("html");
+        let mut loaded = loader.reload(&registry).unwrap();
+        loaded.sort();
+        assert_eq!(loaded, ["emails/header", "emails/welcome"]);
+        assert_eq!(render(&registry, "emails/welcome"), "<h1>Bob</h1>49");
+        assert_eq!(loader.reload(&registry), Ok(vec![]));
+
+        // a broken edit is reported, and the templates keep rendering as they did
+        fs::write(root.join("emails/header.html"), "<h2>{{nmae}}</h2>\n").unwrap();
+        let err = loader.reload(&registry).unwrap_err();
+        assert!(err.contains(r#"template "emails/welcome": in partial emails/header"#));
+        assert_eq!(render(&registry, "emails/welcome"), "<h1>Bob</h1>49");
+
+        fs::write(
+            root.join("emails/header.html"),
+            "<h2>{{name | toupper}}</h2>",
+        )
+        .unwrap();
+        assert_eq!(loader.reload(&registry).unwrap(), ["emails/header"]);
+        assert_eq!(render(&registry, "emails/welcome"), "<h2>BOB</h2>49");
+
+        fs::remove_file(root.join("emails/welcome.html")).unwrap();
+        assert_eq!(loader.reload(&registry).unwrap(), ["emails/welcome"]);
+        assert!(!registry.contains("emails/welcome"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
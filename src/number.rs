@@ -0,0 +1,109 @@
+// allocation-free formatting of numbers for the VM. Integral values go through itoa and everything else through
+// ryu, both of which produce the same shortest round-trip digits as `Display` for f64, so output is unchanged.
+
+use itoa;
+use ryu;
+use std::fmt::Write;
+
+/// Scratch space for formatting a single number. It lives on the stack and is cheap to create, so a fresh one can
+/// be made wherever a number is printed.
+pub struct NumBuffer {
+    int: itoa::Buffer,
+    float: ryu::Buffer,
+    // only used for huge integers and for the rare values where ryu would switch to exponent notation, which
+    // `Display` never does
+    fallback: String,
+}
+
+// integral values up to 2^53 are exact, and `Display` prints every one of their digits. Past that point it prints
+// the shortest round-trip digits padded out with zeros instead, so those have to go through the fallback.
+const MAX_EXACT_INT: f64 = 9_007_199_254_740_992.0;
+
+impl NumBuffer {
+    pub fn new() -> NumBuffer {
+        NumBuffer {
+            int: itoa::Buffer::new(),
+            float: ryu::Buffer::new(),
+            fallback: String::new(),
+        }
+    }
+
+    /// Formats `num` exactly the way `format!("{}", num)` would.
+    pub fn format(&mut self, num: f64) -> &str {
+        if num.is_nan() {
+            return "NaN";
+        }
+        if num.is_infinite() {
+            return if num > 0.0 { "inf" } else { "-inf" };
+        }
+        if num == 0.0 && num.is_sign_negative() {
+            return "-0";
+        }
+        if num.trunc() == num {
+            if num.abs() <= MAX_EXACT_INT {
+                return self.int.format(num as i64);
+            }
+        } else {
+            let formatted = self.float.format_finite(num);
+            if !formatted.contains('e') {
+                return formatted;
+            }
+        }
+
+        self.fallback.clear();
+        write!(self.fallback, "{}", num).expect("writing to a String cannot fail");
+        &self.fallback
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NumBuffer;
+    use std::f64;
+
+    #[test]
+    fn matches_display() {
+        let mut nums = vec![
+            0.0,
+            -0.0,
+            1.0,
+            -1.0,
+            49.0,
+            170.3,
+            77.40909090909092,
+            0.1,
+            0.30000000000000004,
+            1e-5,
+            1.5e-7,
+            1e15,
+            1e16,
+            1.2345e17,
+            1e21,
+            1e300,
+            5e-324,
+            9_007_199_254_740_991.0,
+            9_007_199_254_740_992.0,
+            9_007_199_254_740_994.0,
+            9_223_372_036_854_775_807.0,
+            -9_223_372_036_854_775_808.0,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::EPSILON,
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        // a spread of values across many magnitudes
+        let mut x = 1.0f64;
+        for _ in 0..200 {
+            x *= -1.37;
+            nums.push(x);
+            nums.push(1.0 / x);
+        }
+
+        let mut buffer = NumBuffer::new();
+        for num in nums {
+            assert_eq!(buffer.format(num), format!("{}", num));
+        }
+    }
+}
@@ -0,0 +1,213 @@
+// settings for `compile_with`. Other than the template syntax, everything here only changes how a template is
+// compiled, never what it renders.
+
+use ast::{Expr, Span};
+use optimizer::{self, Pass};
+use tokenizer::Syntax;
+
+/// Controls which optimizations `compile_with` runs and how hard they try. `CompileOptions::default()` compiles
+/// exactly like `compile` does.
+pub struct CompileOptions {
+    pub(crate) optimize: bool,
+    pub(crate) passes: Vec<Pass>,
+    pub(crate) effort: u32,
+    pub(crate) peephole: bool,
+    pub(crate) memoize: bool,
+    pub(crate) syntax: Syntax,
+}
+
+impl Default for CompileOptions {
+    fn default() -> CompileOptions {
+        CompileOptions {
+            optimize: true,
+            passes: Pass::defaults(),
+            effort: optimizer::DEFAULT_EFFORT,
+            peephole: true,
+            memoize: true,
+            syntax: Syntax::default(),
+        }
+    }
+}
+
+impl CompileOptions {
+    pub fn new() -> CompileOptions {
+        CompileOptions::default()
+    }
+
+    /// Turns every optimization on or off. With optimizations off, the bytecode follows the template one
+    /// expression at a time, which makes it much easier to read in a disassembly.
+    pub fn optimize(mut self, optimize: bool) -> CompileOptions {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Replaces the list of passes run over the template, in order.
+    pub fn passes(mut self, passes: Vec<Pass>) -> CompileOptions {
+        self.passes = passes;
+        self
+    }
+
+    /// Adds a pass to run after the ones already registered.
+    pub fn pass<F>(mut self, pass: F) -> CompileOptions
+    where
+        F: for<'x> Fn(Vec<(Expr<'x>, Span)>) -> Vec<(Expr<'x>, Span)> + Send + Sync + 'static,
+    {
+        self.passes.push(Pass::Custom(Box::new(pass)));
+        self
+    }
+
+    /// How deep into each expression constant folding goes. Zero disables folding, though environment constants
+    /// are still substituted.
+    pub fn effort(mut self, effort: u32) -> CompileOptions {
+        self.effort = effort;
+        self
+    }
+
+    /// Turns the peephole pass over the finished bytecode on or off.
+    pub fn peephole(mut self, peephole: bool) -> CompileOptions {
+        self.peephole = peephole;
+        self
+    }
+
+    /// Turns on or off saving values the template uses more than once, so the runner is only asked for them once
+    /// per render.
+    pub fn memoize(mut self, memoize: bool) -> CompileOptions {
+        self.memoize = memoize;
+        self
+    }
+
+    /// Turns on or off removing the newline after a tag that doesn't print anything itself, such as
+    /// `{{#block name}}` or `{{> partial}}`, along with the indentation before it. This works like Jinja's
+    /// `trim_blocks` and `lstrip_blocks` together.
+    pub fn trim_blocks(mut self, trim_blocks: bool) -> CompileOptions {
+        self.syntax.trim_blocks = trim_blocks;
+        self
+    }
+
+    /// Uses other delimiters than `{{` and `}}` around substitution blocks, such as `<%` and `%>`. Partials and base
+    /// templates are expected to use the same delimiters.
+    ///
+    /// Fails if either delimiter is empty or contains whitespace.
+    pub fn delimiters(mut self, open: &str, close: &str) -> Result<CompileOptions, String> {
+        let mut syntax = Syntax::new(open, close)?;
+        syntax.trim_blocks = self.syntax.trim_blocks;
+        self.syntax = syntax;
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile_with;
+    use test_support::*;
+
+    const TEMPLATE: &str = "{{provider}} {{provider_code + 4}} {{name}}";
+
+    fn listing(options: &CompileOptions) -> Vec<String> {
+        let env = provider();
+        let mut bytecode = compile_with(TEMPLATE, &env, options).unwrap();
+        assert_eq!(bytecode.render_to_string(&person()), "john doe 35 Bob");
+        bytecode
+            .disassemble()
+            .into_iter()
+            .map(|instr| instr.instr)
+            .collect()
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(
+            listing(&CompileOptions::default()),
+            [r#"PrintRaw "john doe 35 ""#, "PrintStr name"]
+        );
+    }
+
+    #[test]
+    fn unoptimized() {
+        assert_eq!(
+            listing(&CompileOptions::new().optimize(false)),
+            [
+                r#"PrintRaw "john doe""#,
+                r#"PrintRaw " ""#,
+                "PushImm 31",
+                "PushImm 4",
+                "Add",
+                "PrintReg",
+                r#"PrintRaw " ""#,
+                "PrintStr name",
+            ]
+        );
+
+        // constants are still substituted when folding is given no effort to spend
+        assert_eq!(
+            listing(&CompileOptions::new().effort(0).peephole(false))[1..4],
+            ["PushImm 31", "PushImm 4", "Add"]
+        );
+    }
+
+    fn redact<'x>(ast: Vec<(Expr<'x>, Span)>) -> Vec<(Expr<'x>, Span)> {
+        ast.into_iter()
+            .map(|(tree, span)| match tree {
+                Expr::Identifier(_) => (Expr::StringLiteral("someone".into()), span),
+                tree => (tree, span),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn custom_passes() {
+        let env = provider();
+        let compile = |options: &CompileOptions| {
+            let mut bytecode = compile_with(TEMPLATE, &env, options).unwrap();
+            let output = bytecode.render_to_string(&person());
+            (output, bytecode.disassemble().len())
+        };
+
+        // registered after string merging, so the placeholder is left on its own
+        let options = CompileOptions::new().peephole(false).pass(redact);
+        assert_eq!(compile(&options), ("john doe 35 someone".to_string(), 2));
+
+        let options = CompileOptions::new().peephole(false).passes(vec![
+            Pass::ConstantFolding,
+            Pass::Custom(Box::new(redact)),
+            Pass::MergeStrings,
+        ]);
+        assert_eq!(compile(&options), ("john doe 35 someone".to_string(), 1));
+    }
+
+    #[test]
+    fn delimiters() {
+        let env = provider();
+        let template = r"\begin{<%name%>} {{id}} <%= [[ ]] =%>[[age | sqrt -]]  [[! x ]]{{{}}}";
+        let options = CompileOptions::new().delimiters("<%", "%>").unwrap();
+        let mut bytecode = compile_with(template, &env, &options).unwrap();
+        assert_eq!(
+            bytecode.render_to_string(&person()),
+            r"\begin{Bob} {{id}} 7{{{}}}"
+        );
+
+        // empty delimiters would never match anything, so they're rejected up front
+        let err = CompileOptions::new().delimiters("<%", "").err();
+        assert_eq!(err, Some(r#"Invalid delimiter """#.to_string()));
+    }
+
+    #[test]
+    fn trim_blocks() {
+        let env = provider();
+        let template = "<ul>\n  {{! one per person }}\n  {{#block items}}\n  <li>{{name}}</li>\n  {{/block}}\n</ul>\n";
+        let render = |options: &CompileOptions| {
+            let mut bytecode = compile_with(template, &env, options).unwrap();
+            bytecode.render_to_string(&person())
+        };
+
+        assert_eq!(
+            render(&CompileOptions::default()),
+            "<ul>\n  \n  \n  <li>Bob</li>\n  \n</ul>\n"
+        );
+        assert_eq!(
+            render(&CompileOptions::new().trim_blocks(true)),
+            "<ul>\n  <li>Bob</li>\n</ul>\n"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+// partial templates, pulled in with `{{> name}}`. Includes are resolved before the template is optimized: each one
+// is replaced by the partial's own expressions, so the partial's text ends up in the including template's raw_text
+// and its expressions are optimized alongside everything else. The inlined expressions keep the span of the include
+// that pulled them in, which is what errors and the disassembly point at.
+
+use ast::{self, Expr, Span};
+use std::collections::HashMap;
+use tokenizer::{Syntax, Tokenizer};
+
+pub(crate) type SpannedAst<'a> = Vec<(Expr<'a>, Span)>;
+
+/// Looks up the source of the templates that `{{> name}}` includes and `{{extends "name"}}` builds on.
+pub trait Partials {
+    fn partial(&self, name: &str) -> Option<&str>;
+}
+
+impl Partials for HashMap<String, String> {
+    fn partial(&self, name: &str) -> Option<&str> {
+        self.get(name).map(|source| source.as_str())
+    }
+}
+
+impl<'p> Partials for HashMap<&'p str, &'p str> {
+    fn partial(&self, name: &str) -> Option<&str> {
+        self.get(name).cloned()
+    }
+}
+
+/// An empty set of partials, for templates that don't include any.
+pub struct NoPartials;
+
+impl Partials for NoPartials {
+    fn partial(&self, _name: &str) -> Option<&str> {
+        None
+    }
+}
+
+/// Where a partial was included from in the top level template, or where it extended a base template.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IncludeSite {
+    pub span: Span,
+    /// the partial that was included, followed by any partials it included in turn. For a base template, the
+    /// template it extends, followed by any that one extends in turn.
+    pub chain: Vec<String>,
+    pub extends: bool,
+}
+
+impl IncludeSite {
+    /// Explains that `err` happened inside of this include.
+    pub fn describe(&self, err: &str) -> String {
+        let (kind, action) = if self.extends {
+            ("base template", "extended")
+        } else {
+            ("partial", "included")
+        };
+        format!(
+            "in {} {} {} at template {}..{}: {}",
+            kind,
+            self.chain.join(" > "),
+            action,
+            self.span.start,
+            self.span.end,
+            err
+        )
+    }
+}
+
+/// Replaces every include in the template with the partial it names, recursively. Along with the expanded
+/// template, this returns the sites of the top level includes, which errors from the inlined expressions can be
+/// matched back to by their span. Partials are tokenized with the same `syntax` as the template.
+pub fn expand<'a>(
+    ast: SpannedAst<'a>,
+    partials: &'a dyn Partials,
+    syntax: &Syntax,
+) -> Result<(SpannedAst<'a>, Vec<IncludeSite>), String> {
+    let mut expanded = Vec::with_capacity(ast.len());
+    let mut sites = Vec::new();
+    for (tree, span) in ast {
+        match tree {
+            Expr::Include(name) => {
+                let mut site = IncludeSite {
+                    span,
+                    chain: vec![name.to_string()],
+                    extends: false,
+                };
+                inline(name, partials, syntax, &mut site, &mut expanded)?;
+                sites.push(site);
+            }
+            tree => expanded.push((tree, span)),
+        }
+    }
+    Ok((expanded, sites))
+}
+
+// inlines the last partial in site.chain, which is always `name`
+fn inline<'a>(
+    name: &'a str,
+    partials: &'a dyn Partials,
+    syntax: &Syntax,
+    site: &mut IncludeSite,
+    expanded: &mut SpannedAst<'a>,
+) -> Result<(), String> {
+    let source = match partials.partial(name) {
+        Some(source) => source,
+        None => return Err(site.describe("no partial with that name exists")),
+    };
+    let ast = ast::parse_spanned(Tokenizer::with_syntax(source, syntax.clone()))
+        .map_err(|err| site.describe(&err))?;
+
+    for (tree, _) in ast {
+        match tree {
+            Expr::Include(inner) => {
+                if site.chain.iter().any(|included| included == inner) {
+                    let cycle = format!("including {:?} again would never end", inner);
+                    return Err(site.describe(&cycle));
+                }
+                site.chain.push(inner.to_string());
+                inline(inner, partials, syntax, site, expanded)?;
+                site.chain.pop();
+            }
+            Expr::Extends(_) => return Err(site.describe("partials can't extend another template")),
+            tree => expanded.push((tree, site.span)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile_with_partials;
+    use test_support::*;
+    use CompileOptions;
+
+    fn partials(list: &[(&'static str, &'static str)]) -> HashMap<&'static str, &'static str> {
+        list.iter().cloned().collect()
+    }
+
+    #[test]
+    fn inlines_partials() {
+        let env = provider();
+        let partials = partials(&[
+            ("header", "<h1>{{> title}}</h1>\n"),
+            ("title", "{{name | toupper}} ({{provider}})"),
+            ("footer", "\n-- {{age}}"),
+        ]);
+        let template = "{{> header}}{{id}}{{>footer}}";
+        let mut bytecode =
+            compile_with_partials(template, &env, &partials, &CompileOptions::default()).unwrap();
+        assert_eq!(
+            bytecode.render_to_string(&person()),
+            "<h1>BOB (john doe)</h1>\n12\n-- 49"
+        );
+
+        // the partials' text is merged with the text around it, and spans point at the includes
+        let listing = bytecode
+            .disassemble()
+            .into_iter()
+            .map(|instr| (instr.instr, &template[instr.span.start..instr.span.end]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listing,
+            [
+                (r#"PrintRaw "<h1>""#.to_string(), "{{> header}}"),
+                ("CallStr toupper() name".to_string(), "{{> header}}"),
+                (
+                    r#"PrintRaw " (john doe)</h1>\n""#.to_string(),
+                    "{{> header}}"
+                ),
+                ("PrintNum id".to_string(), "{{id}}"),
+                (r#"PrintRaw "\n-- ""#.to_string(), "{{>footer}}"),
+                ("PrintNum age".to_string(), "{{>footer}}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_include() {
+        let env = provider();
+        let options = CompileOptions::default();
+        let partials = partials(&[
+            ("a", "{{> b}}"),
+            ("b", "x{{> a}}"),
+            ("typo", "{{nmae}}"),
+            ("broken", "{{age +}}"),
+        ]);
+        let compile = |template| {
+            compile_with_partials(template, &env, &partials, &options)
+                .map(|_| ())
+                .unwrap_err()
+        };
+
+        assert_eq!(
+            compile("ab {{> a}}"),
+            r#"in partial a > b included at template 3..10: including "a" again would never end"#
+        );
+        assert_eq!(
+            compile("{{> missing}}"),
+            "in partial missing included at template 0..13: no partial with that name exists"
+        );
+        assert_eq!(
+            compile("{{id}} {{> typo}}"),
+            r#"in partial typo included at template 7..17: Unknown identifier "nmae""#
+        );
+        assert!(
+            compile("{{> broken}}").starts_with("in partial broken included at template 0..12: ")
+        );
+        assert_eq!(
+            compile("{{>}}"),
+            "Error: Expected the name of a partial after >"
+        );
+    }
+}
@@ -0,0 +1,474 @@
+// a collection of named templates, compiled against one environment. Templates are compiled the first time they're
+// used and the Bytecode is cached until the source of the template, or of anything it includes or extends, changes.
+// The registry only needs a shared reference for everything, so it can be shared between threads as it is. Templates
+// used for the first time are compiled without holding the lock, so a slow compile never holds up renders of other
+// templates.
+
+use bytecode::Bytecode;
+use partials::Partials;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use {compile_with_partials, CompileOptions, Environment, Runner};
+
+type Compiled<NumEnum, StrEnum, FilterEnum> =
+    Result<Arc<Bytecode<NumEnum, StrEnum, FilterEnum>>, String>;
+
+struct Template<NumEnum, StrEnum, FilterEnum> {
+    source: String,
+    compiled: Option<Compiled<NumEnum, StrEnum, FilterEnum>>,
+    // the names of the templates that compiling this one looked up, directly or not
+    uses: Vec<String>,
+}
+
+/// A set of named templates that can include and extend each other, all compiled against the same environment.
+pub struct Registry<NumEnum, StrEnum, FilterEnum, Env> {
+    environment: Env,
+    options: CompileOptions,
+    templates: RwLock<HashMap<String, Template<NumEnum, StrEnum, FilterEnum>>>,
+    // bumped whenever a template's source changes, so a compile that started before then can tell it's out of date
+    generation: AtomicUsize,
+}
+
+// serves the registry's templates as partials, keeping track of every name that was asked for
+struct Lookup<'r, NumEnum: 'r, StrEnum: 'r, FilterEnum: 'r> {
+    templates: &'r HashMap<String, Template<NumEnum, StrEnum, FilterEnum>>,
+    uses: RefCell<Vec<String>>,
+}
+
+impl<'r, NumEnum, StrEnum, FilterEnum> Partials for Lookup<'r, NumEnum, StrEnum, FilterEnum> {
+    fn partial(&self, name: &str) -> Option<&str> {
+        self.uses.borrow_mut().push(name.to_string());
+        self.templates
+            .get(name)
+            .map(|template| template.source.as_str())
+    }
+}
+
+impl<NumEnum, StrEnum, FilterEnum, Env> Registry<NumEnum, StrEnum, FilterEnum, Env>
+where
+    NumEnum: 'static + Send + Sync + Copy + Debug,
+    StrEnum: 'static + Send + Sync + Copy + Debug + PartialEq,
+    FilterEnum: 'static + Send + Sync + Copy + Debug,
+    Env: for<'a> Environment<'a, NumEnum, StrEnum, FilterEnum>,
+{
+    pub fn new(environment: Env) -> Registry<NumEnum, StrEnum, FilterEnum, Env> {
+        Registry::with_options(environment, CompileOptions::default())
+    }
+
+    /// Creates a registry that compiles every template with `options`.
+    pub fn with_options(
+        environment: Env,
+        options: CompileOptions,
+    ) -> Registry<NumEnum, StrEnum, FilterEnum, Env> {
+        Registry {
+            environment,
+            options,
+            templates: RwLock::new(HashMap::new()),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn environment(&self) -> &Env {
+        &self.environment
+    }
+
+    /// Adds a template, or replaces the source of an existing one. Nothing is compiled until the template is used,
+    /// but any cached Bytecode that included or extended this template is thrown away.
+    pub fn insert(&self, name: &str, source: &str) {
+        let mut templates = self.templates.write().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        invalidate(&mut templates, name);
+        templates.insert(
+            name.to_string(),
+            Template {
+                source: source.to_string(),
+                compiled: None,
+                uses: Vec::new(),
+            },
+        );
+    }
+
+    /// Removes a template, returning whether it existed.
+    pub fn remove(&self, name: &str) -> bool {
+        let mut templates = self.templates.write().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        invalidate(&mut templates, name);
+        templates.remove(name).is_some()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.templates.read().unwrap().contains_key(name)
+    }
+
+    /// The names of every template in the registry, in no particular order.
+    pub fn names(&self) -> Vec<String> {
+        self.templates.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns the compiled template, compiling it first if it hasn't been used since it last changed. Compile
+    /// errors are cached just like Bytecode is, so a broken template isn't compiled again until something changes.
+    pub fn get(&self, name: &str) -> Compiled<NumEnum, StrEnum, FilterEnum> {
+        loop {
+            // a copy of every template's source, so the lock isn't held while compiling
+            let (generation, mut sources) = {
+                let templates = self.templates.read().unwrap();
+                match templates.get(name) {
+                    Some(&Template {
+                        compiled: Some(ref compiled),
+                        ..
+                    }) => return compiled.clone(),
+                    Some(_) => {}
+                    None => return Err(format!("No template named {:?}", name)),
+                }
+                let sources = templates
+                    .iter()
+                    .map(|(name, template)| {
+                        let template = Template {
+                            source: template.source.clone(),
+                            compiled: None,
+                            uses: Vec::new(),
+                        };
+                        (name.clone(), template)
+                    })
+                    .collect::<HashMap<_, _>>();
+                (self.generation.load(Ordering::SeqCst), sources)
+            };
+
+            let compiled = self.compile(&mut sources, name);
+            let mut templates = self.templates.write().unwrap();
+            if self.generation.load(Ordering::SeqCst) != generation {
+                // a template changed while this one was compiling, so the result may be out of date
+                continue;
+            }
+            let template = templates.get_mut(name).unwrap();
+            match template.compiled {
+                // someone else compiled it at the same time, in which case this just returns theirs
+                Some(ref compiled) => return compiled.clone(),
+                None => {
+                    template.compiled = Some(compiled.clone());
+                    template.uses = sources.remove(name).unwrap().uses;
+                    return compiled;
+                }
+            }
+        }
+    }
+
+    /// Compiles every template that isn't already compiled, so that problems show up before anything is rendered.
+    /// The error lists every template that failed, one per line.
+    pub fn compile_all(&self) -> Result<(), String> {
+        let mut templates = self.templates.write().unwrap();
+        let mut names = templates.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+
+        let errors = names
+            .iter()
+            .filter_map(|name| {
+                self.compile(&mut templates, name)
+                    .err()
+                    .map(|err| format!("template {:?}: {}", name, err))
+            })
+            .collect::<Vec<_>>();
+        report(errors)
+    }
+
+    /// Applies a batch of changes, where `Some` adds or replaces the source of a template and `None` removes it.
+    /// Unlike `insert`, the changed templates and every compiled template that used them are compiled again
+    /// straight away. A template that fails to compile keeps the last Bytecode that did compile, if it had one, and
+    /// the error lists every template that failed, one per line.
+    pub fn update(&self, changes: &[(String, Option<String>)]) -> Result<(), String> {
+        let mut templates = self.templates.write().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let mut last_good = HashMap::new();
+        let mut recompile = Vec::new();
+        for (name, source) in changes {
+            for (template_name, template) in templates.iter() {
+                if template_name == name || template.uses.iter().any(|used| used == name) {
+                    if let Some(Ok(ref bytecode)) = template.compiled {
+                        last_good
+                            .entry(template_name.clone())
+                            .or_insert_with(|| bytecode.clone());
+                        recompile.push(template_name.clone());
+                    }
+                }
+            }
+
+            invalidate(&mut templates, name);
+            match source {
+                Some(source) => {
+                    let template = Template {
+                        source: source.clone(),
+                        compiled: None,
+                        uses: Vec::new(),
+                    };
+                    templates.insert(name.clone(), template);
+                    recompile.push(name.clone());
+                }
+                None => {
+                    templates.remove(name);
+                }
+            }
+        }
+        recompile.sort();
+        recompile.dedup();
+
+        let mut errors = Vec::new();
+        for name in recompile {
+            if !templates.contains_key(&name) {
+                continue;
+            }
+            if let Err(err) = self.compile(&mut templates, &name) {
+                if let Some(bytecode) = last_good.remove(&name) {
+                    templates.get_mut(&name).unwrap().compiled = Some(Ok(bytecode));
+                }
+                errors.push(format!("template {:?}: {}", name, err));
+            }
+        }
+        report(errors)
+    }
+
+    /// Renders the named template, compiling it first if needed. A template that is missing or fails to compile is
+    /// reported as an `InvalidInput` error.
+    pub fn render(
+        &self,
+        name: &str,
+        runner: &dyn Runner<NumEnum, StrEnum, FilterEnum>,
+        output: &mut dyn Write,
+    ) -> io::Result<()> {
+        self.render_with(name, runner, output, &mut Vec::new(), &mut String::new())
+    }
+
+    /// Renders the named template like `render`, using externally provided buffers like `Bytecode::render_with`.
+    pub fn render_with(
+        &self,
+        name: &str,
+        runner: &dyn Runner<NumEnum, StrEnum, FilterEnum>,
+        output: &mut dyn Write,
+        stack: &mut Vec<f64>,
+        buffer: &mut String,
+    ) -> io::Result<()> {
+        let bytecode = self
+            .get(name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        bytecode.render_with(runner, output, stack, buffer)
+    }
+
+    fn compile(
+        &self,
+        templates: &mut HashMap<String, Template<NumEnum, StrEnum, FilterEnum>>,
+        name: &str,
+    ) -> Compiled<NumEnum, StrEnum, FilterEnum> {
+        let (compiled, uses) = match templates.get(name) {
+            Some(&Template {
+                compiled: Some(ref compiled),
+                ..
+            }) => return compiled.clone(),
+            Some(template) => {
+                let lookup = Lookup {
+                    templates,
+                    uses: RefCell::new(Vec::new()),
+                };
+                let compiled = compile_with_partials(
+                    &template.source,
+                    &self.environment,
+                    &lookup,
+                    &self.options,
+                );
+                (compiled.map(Arc::new), lookup.uses.into_inner())
+            }
+            None => return Err(format!("No template named {:?}", name)),
+        };
+
+        let template = templates.get_mut(name).unwrap();
+        template.compiled = Some(compiled.clone());
+        template.uses = uses;
+        compiled
+    }
+}
+
+fn report(errors: Vec<String>) -> Result<(), String> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+// forgets the compiled form of `name` and of everything that used it
+fn invalidate<NumEnum, StrEnum, FilterEnum>(
+    templates: &mut HashMap<String, Template<NumEnum, StrEnum, FilterEnum>>,
+    name: &str,
+) {
+    for (template_name, template) in templates.iter_mut() {
+        if template_name == name || template.uses.iter().any(|used| used == name) {
+            template.compiled = None;
+            template.uses.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use test_support::*;
+
+    fn render(
+        registry: &Registry<PersonNums, PersonStrs, PersonFilters, Provider>,
+        name: &str,
+    ) -> String {
+        let mut output = Vec::new();
+        registry.render(name, &person(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn compiles_lazily_and_caches() {
+        let registry = Registry::new(provider());
+        registry.insert("header", "<h1>{{name}}</h1>");
+        registry.insert("page", "{{> header}}{{age}}");
+        registry.insert("broken", "{{nmae}}");
+
+        assert_eq!(render(&registry, "page"), "<h1>Bob</h1>49");
+        let page = registry.get("page").unwrap();
+        assert!(Arc::ptr_eq(&page, &registry.get("page").unwrap()));
+
+        // changing a partial recompiles the templates that include it, and nothing else
+        let header = registry.get("header").unwrap();
+        registry.insert("header", "<h2>{{name | toupper}}</h2>");
+        registry.insert("broken", "{{id}}");
+        assert_eq!(render(&registry, "page"), "<h2>BOB</h2>49");
+        assert!(!Arc::ptr_eq(&page, &registry.get("page").unwrap()));
+        assert!(!Arc::ptr_eq(&header, &registry.get("header").unwrap()));
+
+        let err = registry
+            .render("missing", &person(), &mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), r#"No template named "missing""#);
+    }
+
+    #[test]
+    fn compile_all_reports_every_error() {
+        let registry = Registry::new(provider());
+        registry.insert("fine", "{{name}}");
+        registry.insert("typo", "{{nmae}}");
+        registry.insert("page", r#"{{extends "layout"}}"#);
+        assert_eq!(
+            registry.compile_all().unwrap_err(),
+            "template \"page\": in base template layout extended at template 0..20: no template with that name exists\n\
+             template \"typo\": Unknown identifier \"nmae\""
+        );
+
+        // adding the missing template fixes the one that extends it
+        registry.insert("layout", "<body>{{#block body}}{{/block}}</body>");
+        registry.remove("typo");
+        assert_eq!(registry.compile_all(), Ok(()));
+        assert_eq!(render(&registry, "page"), "<body></body>");
+    }
+
+    #[test]
+    fn update_keeps_the_last_good_bytecode() {
+        let registry = Registry::new(provider());
+        let update = |changes: &[(&str, Option<&str>)]| {
+            let changes = changes
+                .iter()
+                .map(|&(name, source)| (name.to_string(), source.map(|source| source.to_string())))
+                .collect::<Vec<_>>();
+            registry.update(&changes)
+        };
+
+        // a child that comes before its base in the batch still compiles
+        let page = r#"{{extends "layout"}}{{#block body}}{{age}}{{/block}}"#;
+        update(&[
+            ("page", Some(page)),
+            ("layout", Some("[{{#block body}}{{/block}}]")),
+        ])
+        .unwrap();
+        assert_eq!(render(&registry, "page"), "[49]");
+
+        assert_eq!(
+            update(&[("layout", Some("[{{#block body}}{{/block}}{{nmae}}]"))]).unwrap_err(),
+            "template \"layout\": Unknown identifier \"nmae\"\n\
+             template \"page\": in base template layout extended at template 0..20: Unknown identifier \"nmae\""
+        );
+        assert_eq!(render(&registry, "page"), "[49]");
+
+        update(&[("layout", Some("<{{#block body}}{{/block}}>"))]).unwrap();
+        assert_eq!(render(&registry, "page"), "<49>");
+
+        assert!(update(&[("layout", None)]).is_err());
+        assert_eq!(render(&registry, "page"), "<49>");
+        assert!(!registry.contains("layout"));
+    }
+
+    #[test]
+    fn compiles_without_the_lock() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Barrier;
+
+        // once armed, compiling waits for the test to check the registry in the middle of it
+        let armed = Arc::new(AtomicBool::new(false));
+        let barrier = Arc::new(Barrier::new(2));
+        let options = {
+            let (armed, barrier) = (armed.clone(), barrier.clone());
+            CompileOptions::new().pass(move |ast| {
+                if armed.load(Ordering::SeqCst) {
+                    barrier.wait();
+                    barrier.wait();
+                }
+                ast
+            })
+        };
+        let registry = Arc::new(Registry::with_options(provider(), options));
+        registry.insert("fast", "{{name}}");
+        registry.insert("slow", "{{age}}");
+        assert_eq!(render(&registry, "fast"), "Bob");
+
+        armed.store(true, Ordering::SeqCst);
+        let slow = {
+            let registry = registry.clone();
+            thread::spawn(move || render(&registry, "slow"))
+        };
+        barrier.wait();
+        assert!(registry.templates.try_write().is_ok());
+        assert_eq!(render(&registry, "fast"), "Bob");
+        barrier.wait();
+        assert_eq!(slow.join().unwrap(), "49");
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let registry = Arc::new(Registry::new(provider()));
+        registry.insert("greeting", "hello {{name}} ({{age}})");
+
+        let threads = (0..4)
+            .map(|_| {
+                let registry = registry.clone();
+                thread::spawn(move || {
+                    let (mut stack, mut buffer) = (Vec::new(), String::new());
+                    let mut output = Vec::new();
+                    for _ in 0..10 {
+                        registry
+                            .render_with(
+                                "greeting",
+                                &person(),
+                                &mut output,
+                                &mut stack,
+                                &mut buffer,
+                            )
+                            .unwrap();
+                    }
+                    String::from_utf8(output).unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), "hello Bob (49)".repeat(10));
+        }
+    }
+}
@@ -0,0 +1,183 @@
+// the future returned by `Bytecode::render_async`. Rendering itself is synchronous and fast, so items are rendered
+// into an internal buffer which is only handed to the AsyncWrite once it grows past the flush threshold.
+
+use super::{Bytecode, Runner};
+use futures_io::AsyncWrite;
+use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub struct RenderAsync<
+    'r,
+    NumEnum: 'r,
+    StrEnum: 'r,
+    FilterEnum: 'r,
+    RunnerItem: 'r,
+    Writer: 'r + ?Sized,
+> {
+    bytecode: &'r Bytecode<NumEnum, StrEnum, FilterEnum>,
+    runner: &'r [RunnerItem],
+    output: &'r mut Writer,
+    flush_threshold: usize,
+    next_item: usize,
+    write_buf: Vec<u8>,
+    written: usize,
+    stack: Vec<f64>,
+    buffer: String,
+}
+
+impl<'r, NumEnum, StrEnum, FilterEnum, RunnerItem, Writer: ?Sized>
+    RenderAsync<'r, NumEnum, StrEnum, FilterEnum, RunnerItem, Writer>
+{
+    pub(crate) fn new(
+        bytecode: &'r Bytecode<NumEnum, StrEnum, FilterEnum>,
+        runner: &'r [RunnerItem],
+        output: &'r mut Writer,
+        flush_threshold: usize,
+    ) -> RenderAsync<'r, NumEnum, StrEnum, FilterEnum, RunnerItem, Writer> {
+        RenderAsync {
+            bytecode,
+            runner,
+            output,
+            flush_threshold,
+            next_item: 0,
+            write_buf: Vec::with_capacity(flush_threshold),
+            written: 0,
+            stack: Vec::with_capacity(8),
+            buffer: String::with_capacity(8),
+        }
+    }
+}
+
+impl<'r, NumEnum, StrEnum, FilterEnum, RunnerItem, Writer> Future
+    for RenderAsync<'r, NumEnum, StrEnum, FilterEnum, RunnerItem, Writer>
+where
+    NumEnum: 'r + Copy + Debug + Send + Sync,
+    StrEnum: 'r + Copy + Debug + Send + Sync + PartialEq,
+    FilterEnum: 'r + Copy + Debug + Send + Sync,
+    RunnerItem: 'r + Runner<NumEnum, StrEnum, FilterEnum>,
+    Writer: 'r + AsyncWrite + Unpin + ?Sized,
+{
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let finished = this.next_item == this.runner.len();
+
+            // drain the buffer once it is big enough, or once there is nothing left to render
+            if this.written < this.write_buf.len()
+                && (finished || this.write_buf.len() >= this.flush_threshold)
+            {
+                let pending = &this.write_buf[this.written..];
+                match Pin::new(&mut *this.output).poll_write(cx, pending) {
+                    Poll::Ready(Ok(0)) => {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "failed to write rendered output",
+                        )))
+                    }
+                    Poll::Ready(Ok(n)) => this.written += n,
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => return Poll::Pending,
+                }
+                if this.written == this.write_buf.len() {
+                    this.write_buf.clear();
+                    this.written = 0;
+                }
+            } else if !finished {
+                let item = &this.runner[this.next_item];
+                this.next_item += 1;
+                this.bytecode.render_with(
+                    item,
+                    &mut this.write_buf,
+                    &mut this.stack,
+                    &mut this.buffer,
+                )?;
+            } else {
+                return Pin::new(&mut *this.output).poll_flush(cx);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use compile;
+    use futures_io::AsyncWrite;
+    use std::future::Future;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+    use test_support::*;
+
+    // accepts at most 3 bytes per write, and is only ready on every other poll
+    struct SlowWriter {
+        data: Vec<u8>,
+        ready: bool,
+        writes: usize,
+        flushed: bool,
+    }
+
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = buf.len().min(3);
+            self.data.extend_from_slice(&buf[..n]);
+            self.writes += 1;
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            self.flushed = true;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn render_async() {
+        let env = provider();
+        let bytecode = compile("{{id}}: {{name}}\n", &env).unwrap();
+        let group = (0..4)
+            .map(|i| Person { id: i, ..person() })
+            .collect::<Vec<_>>();
+
+        let mut output = SlowWriter {
+            data: Vec::new(),
+            ready: false,
+            writes: 0,
+            flushed: false,
+        };
+        {
+            let mut future = bytecode.render_async(&group, &mut output, 16);
+            let mut cx = Context::from_waker(Waker::noop());
+            loop {
+                if let Poll::Ready(result) = Pin::new(&mut future).poll(&mut cx) {
+                    result.unwrap();
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(
+            String::from_utf8(output.data).unwrap(),
+            "0: Bob\n1: Bob\n2: Bob\n3: Bob\n"
+        );
+        assert_eq!(output.writes, 10);
+        assert!(output.flushed);
+    }
+}
@@ -0,0 +1,160 @@
+// a small hand-written environment and runner, mirroring examples/manual_example.rs, for use in unit tests.
+
+use super::{Environment, FilterInput, Runner};
+use std::borrow::Cow;
+
+#[derive(Clone)]
+pub struct Person {
+    pub id: u64,
+    pub name: String,
+    pub age: u32,
+    pub weight: f64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PersonNums {
+    Id,
+    Age,
+    Weight,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PersonStrs {
+    Name,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PersonFilters {
+    Sqrt,
+    ToUpper,
+    Round,
+}
+
+pub struct Provider {
+    pub provider: String,
+    pub provider_code: u32,
+}
+
+pub fn provider() -> Provider {
+    Provider {
+        provider: "john doe".to_string(),
+        provider_code: 31,
+    }
+}
+
+pub fn person() -> Person {
+    Person {
+        id: 12,
+        name: "Bob".to_string(),
+        age: 49,
+        weight: 170.3,
+    }
+}
+
+impl<'a> Environment<'a, PersonNums, PersonStrs, PersonFilters> for Provider {
+    fn num_constant(&self, name: &str) -> Option<f64> {
+        match name {
+            "provider_code" => Some(self.provider_code as f64),
+            _ => None,
+        }
+    }
+
+    fn str_constant(&self, name: &str) -> Option<Cow<'_, str>> {
+        match name {
+            "provider" => Some(Cow::from(&*self.provider)),
+            _ => None,
+        }
+    }
+
+    fn num_var(name: &str) -> Option<PersonNums> {
+        match name {
+            "id" => Some(PersonNums::Id),
+            "age" => Some(PersonNums::Age),
+            "weight" => Some(PersonNums::Weight),
+            _ => None,
+        }
+    }
+
+    fn str_var(name: &str) -> Option<PersonStrs> {
+        match name {
+            "name" => Some(PersonStrs::Name),
+            _ => None,
+        }
+    }
+
+    fn filter(name: &str) -> Option<(PersonFilters, usize, FilterInput<PersonStrs>, bool)> {
+        match name {
+            "sqrt" => Some((PersonFilters::Sqrt, 0, FilterInput::Numeric, true)),
+            "round" => Some((PersonFilters::Round, 1, FilterInput::Numeric, false)),
+            "toupper" => Some((PersonFilters::ToUpper, 0, FilterInput::Stringified, true)),
+            _ => None,
+        }
+    }
+
+    fn pure_filter_num(&self, filter: PersonFilters, _: &[f64], input: f64) -> Option<f64> {
+        match filter {
+            PersonFilters::Sqrt => Some(input.sqrt()),
+            _ => None,
+        }
+    }
+
+    fn pure_filter_str(
+        &self,
+        filter: PersonFilters,
+        _: &[f64],
+        input: &str,
+        buffer: &mut String,
+    ) -> bool {
+        match filter {
+            PersonFilters::ToUpper => {
+                buffer.push_str(&input.to_uppercase());
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Runner<PersonNums, PersonStrs, PersonFilters> for Person {
+    fn num_var(&self, var: PersonNums) -> f64 {
+        match var {
+            PersonNums::Id => self.id as f64,
+            PersonNums::Age => self.age as f64,
+            PersonNums::Weight => self.weight,
+        }
+    }
+
+    fn str_var(&self, var: PersonStrs) -> Cow<'_, str> {
+        match var {
+            PersonStrs::Name => self.name.as_str().into(),
+        }
+    }
+
+    fn filter_num(&self, filter: PersonFilters, args: &[f64], input: f64) -> f64 {
+        match filter {
+            PersonFilters::Sqrt => input.sqrt(),
+            PersonFilters::Round => {
+                let factor = 10u32.pow(args[0] as u32) as f64;
+                (input * factor).round() / factor
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn filter_id(&self, _: PersonFilters, _: &[f64], _: PersonStrs, _: &mut String) {
+        unreachable!()
+    }
+
+    fn filter_str(
+        &self,
+        filter: PersonFilters,
+        _: &[f64],
+        input: Cow<'_, str>,
+        buffer: &mut String,
+    ) {
+        match filter {
+            PersonFilters::ToUpper => buffer.push_str(&input.to_uppercase()),
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,177 @@
+// batches rendered output into vectored writes. Text that outlives the render (the template's static text, and
+// strings borrowed from the runner) is referenced in place, while anything produced on the fly is copied into a
+// scratch buffer, so each flush is a single `write_vectored` call over up to MAX_SEGMENTS slices.
+
+use number::NumBuffer;
+use std::io::{self, IoSlice, Write};
+
+/// the number of slices collected before the batch is written out
+const MAX_SEGMENTS: usize = 64;
+
+#[derive(Copy, Clone)]
+enum Segment<'r> {
+    Borrowed(&'r [u8]),
+    Scratch(usize, usize),
+}
+
+pub(crate) struct VectoredBatch<'r, 's> {
+    segments: [Segment<'r>; MAX_SEGMENTS],
+    len: usize,
+    scratch: &'s mut Vec<u8>,
+}
+
+impl<'r, 's> VectoredBatch<'r, 's> {
+    pub fn new(scratch: &'s mut Vec<u8>) -> VectoredBatch<'r, 's> {
+        scratch.clear();
+        VectoredBatch {
+            segments: [Segment::Scratch(0, 0); MAX_SEGMENTS],
+            len: 0,
+            scratch,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == MAX_SEGMENTS
+    }
+
+    /// queues bytes which stay alive for the whole render without copying them
+    pub fn push_borrowed(&mut self, bytes: &'r [u8]) {
+        if !bytes.is_empty() {
+            self.segments[self.len] = Segment::Borrowed(bytes);
+            self.len += 1;
+        }
+    }
+
+    /// queues a copy of short-lived bytes
+    pub fn push_copied(&mut self, bytes: &[u8]) {
+        let start = self.scratch.len();
+        self.scratch.extend_from_slice(bytes);
+        self.extend_scratch(start);
+    }
+
+    pub fn push_num(&mut self, num: f64) {
+        self.push_copied(NumBuffer::new().format(num).as_bytes());
+    }
+
+    // records scratch[start..] as queued output, merging it into the previous segment when they are contiguous
+    fn extend_scratch(&mut self, start: usize) {
+        let end = self.scratch.len();
+        if start == end {
+            return;
+        }
+        if self.len > 0 {
+            if let Segment::Scratch(_, ref mut prev_end) = self.segments[self.len - 1] {
+                if *prev_end == start {
+                    *prev_end = end;
+                    return;
+                }
+            }
+        }
+        self.segments[self.len] = Segment::Scratch(start, end);
+        self.len += 1;
+    }
+
+    pub fn flush(&mut self, output: &mut dyn Write) -> io::Result<()> {
+        {
+            let mut slices = [IoSlice::new(&[]); MAX_SEGMENTS];
+            for (slice, segment) in slices.iter_mut().zip(&self.segments[..self.len]) {
+                *slice = IoSlice::new(match *segment {
+                    Segment::Borrowed(bytes) => bytes,
+                    Segment::Scratch(start, end) => &self.scratch[start..end],
+                });
+            }
+            write_all_vectored(output, &mut slices[..self.len])?;
+        }
+        self.len = 0;
+        self.scratch.clear();
+        Ok(())
+    }
+}
+
+fn write_all_vectored(output: &mut dyn Write, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    while !slices.is_empty() {
+        match output.write_vectored(slices) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ))
+            }
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use compile;
+    use std::io::{self, IoSlice, Write};
+    use test_support::*;
+
+    // accepts at most `limit` bytes per call, so a flush may have to resume part way through a slice
+    struct ShortWriter {
+        data: Vec<u8>,
+        calls: usize,
+        limit: usize,
+    }
+
+    impl ShortWriter {
+        fn new(limit: usize) -> ShortWriter {
+            ShortWriter {
+                data: Vec::new(),
+                calls: 0,
+                limit,
+            }
+        }
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+            self.calls += 1;
+            let mut written = 0;
+            for buf in bufs {
+                let n = buf.len().min(self.limit - written);
+                self.data.extend_from_slice(&buf[..n]);
+                written += n;
+            }
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn matches_render() {
+        let env = provider();
+        let template =
+            "{{provider}} {{id}} {{name | toupper}} {{weight / 2 | round 1}}kg\n".repeat(40);
+        let mut bytecode = compile(&template, &env).unwrap();
+
+        let mut expected = Vec::new();
+        bytecode.render(&person(), &mut expected).unwrap();
+
+        let mut output = ShortWriter::new(5);
+        bytecode.render_vectored(&person(), &mut output).unwrap();
+        assert_eq!(output.data, expected);
+    }
+
+    #[test]
+    fn batches_writes() {
+        let env = provider();
+        let mut bytecode = compile("{{name}} is {{age}} years old\n", &env).unwrap();
+
+        let mut output = ShortWriter::new(usize::MAX);
+        bytecode.render_vectored(&person(), &mut output).unwrap();
+        assert_eq!(output.data, b"Bob is 49 years old\n");
+        assert_eq!(output.calls, 1);
+    }
+}
@@ -0,0 +1,121 @@
+// templates compiled by the derive have to render exactly like the same template compiled at runtime
+
+#[macro_use]
+extern crate zapper;
+
+use zapper::compile;
+
+const SUMMARY: &str =
+    "{{provider | toupper}} {{provider_code + 4}} {{id}} {{name | toupper}} {{age | sqrt}} {{-weight / 2.2 | round 2}}kg\n";
+
+#[derive(ZapperRunner)]
+#[filter = "sqrt/0n"]
+#[filter = "round/1n"]
+#[filter = "toupper/0s/pure"]
+#[zapper(
+    render = "render_summary",
+    env = "Provider",
+    source = "{{provider | toupper}} {{provider_code + 4}} {{id}} {{name | toupper}} {{age | sqrt}} {{-weight / 2.2 | round 2}}kg\n"
+)]
+#[zapper(render = "render_fields", source = "{{id}}: {{name}} ({{age * 2}})")]
+struct Person {
+    id: u64,
+    name: String,
+    age: u32,
+    weight: f64,
+}
+
+#[derive(ZapperEnv)]
+#[runner = "Person"]
+struct Provider {
+    provider: String,
+    provider_code: u32,
+}
+
+fn sqrt(_data: &Person, _args: &[f64], input: f64) -> f64 {
+    input.sqrt()
+}
+
+fn round(_data: &Person, args: &[f64], input: f64) -> f64 {
+    let factor = 10u32.pow(args[0] as u32) as f64;
+    (input * factor).round() / factor
+}
+
+fn toupper(_args: &[f64], input: &str, buffer: &mut String) {
+    buffer.push_str(&input.to_uppercase());
+}
+
+fn person() -> Person {
+    Person {
+        id: 12,
+        name: "Bob".to_string(),
+        age: 49,
+        weight: 170.3,
+    }
+}
+
+#[test]
+fn matches_compiled_templates() {
+    let env = Provider {
+        provider: "john doe".to_string(),
+        provider_code: 31,
+    };
+
+    let mut output = Vec::new();
+    person().render_summary(&env, &mut output).unwrap();
+    let mut bytecode = compile(SUMMARY, &env).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        bytecode.render_to_string(&person())
+    );
+
+    let mut output = Vec::new();
+    person().render_fields(&mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "12: Bob (98)");
+}
+
+#[test]
+fn renders_function_templates() {
+    let render = zapper_template!(Person, "{{id}}: {{name}} ({{age * 2}})");
+    let mut output = Vec::new();
+    render(&person(), &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "12: Bob (98)");
+}
+
+// the environment's constants are read when rendering rather than when the template is built, so changing the
+// environment afterwards renders like bytecode compiled against the new values
+#[test]
+fn reads_constants_when_rendering() {
+    let render_summary = zapper_template!(
+        Person,
+        Provider,
+        "{{provider | toupper}} {{provider_code + 4}} {{id}} {{name | toupper}} {{age | sqrt}} {{-weight / 2.2 | round 2}}kg\n"
+    );
+    let mut env = Provider {
+        provider: "john doe".to_string(),
+        provider_code: 31,
+    };
+    let render = |env: &Provider| {
+        let mut derived = Vec::new();
+        person().render_summary(env, &mut derived).unwrap();
+        let mut function = Vec::new();
+        render_summary(&person(), env, &mut function).unwrap();
+        assert_eq!(derived, function);
+        String::from_utf8(derived).unwrap()
+    };
+
+    let before = render(&env);
+    assert_eq!(
+        before,
+        compile(SUMMARY, &env).unwrap().render_to_string(&person())
+    );
+
+    env.provider = "jane roe".to_string();
+    env.provider_code = 7;
+    let after = render(&env);
+    assert_ne!(after, before);
+    assert_eq!(
+        after,
+        compile(SUMMARY, &env).unwrap().render_to_string(&person())
+    );
+}
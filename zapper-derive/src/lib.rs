@@ -1,19 +1,27 @@
 #![recursion_limit = "128"]
 
 extern crate proc_macro;
+extern crate proc_macro2;
 extern crate syn;
 #[macro_use]
 extern crate quote;
 
-use syn::{Data, Fields, Ident, Type};
+extern crate zapper_front;
+
+mod template;
+
+use proc_macro2::TokenTree;
+use syn::{Data, Fields, Ident, Lit, Meta, NestedMeta, Type};
 
 use proc_macro::TokenStream;
+use template::{Template, TemplateSource};
 
 struct Analysis {
     filters: Vec<String>,
     num_fields: Vec<Ident>,
     str_fields: Vec<(Ident, bool)>,
     runner: Option<Ident>,
+    templates: Vec<Template>,
 }
 
 impl Analysis {
@@ -21,7 +29,12 @@ impl Analysis {
         let name = ast.ident;
         let mut filters = vec![];
         let mut runner = None;
+        let mut templates = vec![];
         for attr in &ast.attrs {
+            if attr.path.segments[0].ident == "zapper" {
+                templates.push(parse_template(attr));
+                continue;
+            }
             let attr_name = attr.path.segments[0].ident.to_string();
             let val = attr.tts
                 .clone()
@@ -66,10 +79,52 @@ impl Analysis {
             num_fields,
             str_fields,
             runner,
+            templates,
         }
     }
 }
 
+// parses #[zapper(render = "fn_name", template = "path/to/template")], or source = "..." for an inline template. An
+// optional env = "Type" makes the render method take the environment, for templates that use its constants.
+fn parse_template(attr: &syn::Attribute) -> Template {
+    use syn::spanned::Spanned;
+
+    let usage = "Error: Expected #[zapper(render = \"fn_name\", template = \"path\")]";
+    let mut render_fn = None;
+    let mut source = None;
+    let mut env = None;
+    match attr.interpret_meta() {
+        Some(Meta::List(list)) => for item in list.nested {
+            let (key, value) = match item {
+                NestedMeta::Meta(Meta::NameValue(syn::MetaNameValue {
+                    ident,
+                    lit: Lit::Str(value),
+                    ..
+                })) => (ident, value),
+                _ => panic!("{}", usage),
+            };
+            match key.as_ref() {
+                "render" => render_fn = Some(Ident::new(&value.value(), value.span())),
+                "template" => source = Some(TemplateSource::Path(value.value())),
+                "source" => source = Some(TemplateSource::Inline(value.value())),
+                "env" => match syn::parse_str::<Type>(&value.value()) {
+                    Ok(ty) => env = Some(ty),
+                    Err(_) => panic!("env must name the environment's type, found {:?}", value.value()),
+                },
+                _ => panic!("unexpected zapper option {}", key),
+            }
+        },
+        _ => panic!("{}", usage),
+    }
+
+    Template {
+        render_fn: render_fn.expect(usage),
+        source: source.expect(usage),
+        env,
+        span: attr.span(),
+    }
+}
+
 #[proc_macro_derive(ZapperEnv, attributes(runner, zapper_ignore))]
 pub fn zapper_env_derive(input: TokenStream) -> TokenStream {
     // Parse the string representation
@@ -88,9 +143,14 @@ fn impl_zapper_env(ast: syn::DeriveInput) -> quote::Tokens {
         num_fields,
         str_fields,
         runner,
+        templates,
     } = Analysis::from(&ast);
 
     assert_eq!(filters.len(), 0, "ZapperEnv should not have any filters");
+    assert!(
+        templates.is_empty(),
+        "templates are compiled for the ZapperRunner, not the ZapperEnv"
+    );
 
     let name = ast.ident;
 
@@ -148,14 +208,28 @@ fn impl_zapper_env(ast: syn::DeriveInput) -> quote::Tokens {
                 #str_enum::from_str(name)
             }
 
-            fn filter(name: &str) -> Option<(#filter_enum, usize, ::zapper::FilterInput<#str_enum>)> {
+            fn filter(name: &str) -> Option<(#filter_enum, usize, ::zapper::FilterInput<#str_enum>, bool)> {
                 #filter_enum::from_str(name)
             }
+
+            fn pure_filter_num(&self, filter: #filter_enum, args: &[f64], input: f64) -> Option<f64> {
+                filter.pure_num(args, input)
+            }
+
+            fn pure_filter_str(&self, filter: #filter_enum, args: &[f64], input: &str, buffer: &mut String) -> bool {
+                match filter.pure_str(args, input) {
+                    Some(output) => {
+                        buffer.push_str(&output);
+                        true
+                    }
+                    None => false
+                }
+            }
         }
     }
 }
 
-#[proc_macro_derive(ZapperRunner, attributes(filter, zapper_ignore))]
+#[proc_macro_derive(ZapperRunner, attributes(filter, zapper_ignore, zapper))]
 pub fn zapper_runner_derive(input: TokenStream) -> TokenStream {
     // Parse the string representation
     let ast = syn::parse(input).unwrap();
@@ -202,6 +276,7 @@ fn impl_zapper_runner(ast: syn::DeriveInput) -> quote::Tokens {
         num_fields,
         str_fields,
         runner,
+        templates,
     } = Analysis::from(&ast);
 
     assert!(
@@ -221,6 +296,8 @@ fn impl_zapper_runner(ast: syn::DeriveInput) -> quote::Tokens {
             name.span(),
         )
     });
+    let mut pure_num_filters = vec![];
+    let mut pure_str_filters = vec![];
 
     let num_match = num_fields
         .iter()
@@ -258,11 +335,27 @@ fn impl_zapper_runner(ast: syn::DeriveInput) -> quote::Tokens {
     let mut str_filters = vec![];
     let mut custom_filters = vec![];
 
-    let str_fields = str_fields.into_iter().map(|(f, _)| f);
+    let template_fields = template::Fields {
+        runner: name,
+        str_enum,
+        num_fields: &num_fields,
+        str_fields: &str_fields,
+        filters: &filters,
+    };
+    let render_fns = templates
+        .iter()
+        .map(|t| template::impl_template(t, &template_fields))
+        .collect::<Vec<_>>();
+
+    let str_fields = str_fields.iter().map(|&(f, _)| f);
 
     let filter_from = filters
         .iter()
         .map(|f| {
+            // a trailing /pure marks a filter whose output depends only on its arguments and input. Those are plain
+            // functions without the runner, so the environment can run them on constants while compiling.
+            let pure = f.ends_with("/pure");
+            let f = if pure { &f[..f.len() - "/pure".len()] } else { &f[..] };
             let split = f.find('/')
                 .expect("filters must specify number of args and return type.");
             let filter = &f[..split];
@@ -271,24 +364,44 @@ fn impl_zapper_runner(ast: syn::DeriveInput) -> quote::Tokens {
                 .parse::<usize>()
                 .expect("argument count for filter must be a usize");
             let filter_type = f.as_bytes()[f.len() - 1] as char;
-            match filter_type {
-            'n' => {
+            match (filter_type, pure) {
+            ('n', false) => {
                 num_filters.push(quote! { #filter_enum::#filter_i => #filter_i(self, args, input), });
-                quote!( #filter => Some((#filter_enum::#filter_i, #arg_count, ::zapper::FilterInput::Numeric)), )
+                quote!( #filter => Some((#filter_enum::#filter_i, #arg_count, ::zapper::FilterInput::Numeric, false)), )
+            }
+            ('n', true) => {
+                num_filters.push(quote! { #filter_enum::#filter_i => #filter_i(args, input), });
+                pure_num_filters.push(quote! { #filter_enum::#filter_i => Some(#filter_i(args, input)), });
+                quote!( #filter => Some((#filter_enum::#filter_i, #arg_count, ::zapper::FilterInput::Numeric, true)), )
+            }
+            ('s', false) => {
+                str_filters.push(quote! { #filter_enum::#filter_i => #filter_i(self, args, &input, buffer), });
+                quote!( #filter => Some((#filter_enum::#filter_i, #arg_count, ::zapper::FilterInput::Stringified, false)), )
             }
-            's' => {
-                str_filters.push(quote! { #filter_enum::#filter_i => #filter_i(self, args, &input, buffer), });                
-                quote!( #filter => Some((#filter_enum::#filter_i, #arg_count, ::zapper::FilterInput::Stringified)), )
+            ('s', true) => {
+                str_filters.push(quote! { #filter_enum::#filter_i => #filter_i(args, &input, buffer), });
+                pure_str_filters.push(quote! {
+                    #filter_enum::#filter_i => {
+                        let mut output = String::new();
+                        #filter_i(args, input, &mut output);
+                        Some(output)
+                    }
+                });
+                quote!( #filter => Some((#filter_enum::#filter_i, #arg_count, ::zapper::FilterInput::Stringified, true)), )
             }
-            'x' => {
-                custom_filters.push(quote! { #filter_enum::#filter_i => #filter_i(self, args, input_id, buffer), });                                
-                quote!( #filter => Some((#filter_enum::#filter_i, #arg_count, ::zapper::FilterInput::StrEnumId(vec![]))), )
+            ('x', false) => {
+                custom_filters.push(quote! { #filter_enum::#filter_i => #filter_i(self, args, input_id, buffer), });
+                quote!( #filter => Some((#filter_enum::#filter_i, #arg_count, ::zapper::FilterInput::StrEnumId(vec![]), false)), )
             }
+            ('x', true) => panic!("custom filter {} takes a runner's string as input, so it cannot be pure", filter),
             _ => panic!("no such input type as {}, valid options are n (numeric), s (stringified), x (custom)", filter_type)
         }
         })
         .collect::<Vec<_>>();
 
+    // `zapper_template!(Person, ...)` calls this with its arguments, so the template can see the struct's fields
+    let template_macro = Ident::new(&format!("__zapper_template_{}", name), name.span());
+
     // println!(
     //     "{:#?}",
     quote! {
@@ -329,12 +442,26 @@ fn impl_zapper_runner(ast: syn::DeriveInput) -> quote::Tokens {
         }
 
         impl #filter_enum {
-            fn from_str(name: &str) -> Option<(#filter_enum, usize, ::zapper::FilterInput<#str_enum>)> {
+            fn from_str(name: &str) -> Option<(#filter_enum, usize, ::zapper::FilterInput<#str_enum>, bool)> {
                 match name {
                     #(#filter_from)*
                     _ => None
                 }
             }
+
+            fn pure_num(self, args: &[f64], input: f64) -> Option<f64> {
+                match self {
+                    #(#pure_num_filters)*
+                    _ => None
+                }
+            }
+
+            fn pure_str(self, args: &[f64], input: &str) -> Option<String> {
+                match self {
+                    #(#pure_str_filters)*
+                    _ => None
+                }
+            }
         }
 
         #[allow(bad_style, unused)]
@@ -378,7 +505,87 @@ fn impl_zapper_runner(ast: syn::DeriveInput) -> quote::Tokens {
                 }
             }
         }
+
+        #(#render_fns)*
+
+        #[allow(unused_macros)]
+        macro_rules! #template_macro {
+            ($($args:tt)*) => {
+                ::zapper::__zapper_render!({ #ast } $($args)*)
+            };
+        }
     }
     // );
     // unreachable!();
 }
+
+/// Compiles a template into a render function at build time, for a struct that derives ZapperRunner earlier in the
+/// same module: `zapper_template!(Person, "{{name}} is {{age}}")(&person, &mut output)`. Passing the environment's
+/// type as well, as in `zapper_template!(Person, Provider, "...")`, lets the template use its constants, and the
+/// render function then takes the environment after the runner.
+#[proc_macro]
+pub fn zapper_template(input: TokenStream) -> TokenStream {
+    let input = proc_macro2::TokenStream::from(input);
+    let runner = match input.clone().into_iter().next() {
+        Some(TokenTree::Term(runner)) => runner,
+        _ => panic!("{}", TEMPLATE_USAGE),
+    };
+
+    // the derive defines this macro, which passes the runner's definition on to `__zapper_render`
+    let template_macro = Ident::new(&format!("__zapper_template_{}", runner.as_str()), runner.span());
+    let gen = quote! { #template_macro!(#input) };
+    gen.into()
+}
+
+const TEMPLATE_USAGE: &str =
+    "Error: Expected zapper_template!(Runner, \"template\") or zapper_template!(Runner, Env, \"template\")";
+
+#[doc(hidden)]
+#[proc_macro]
+pub fn __zapper_render(input: TokenStream) -> TokenStream {
+    let mut tokens = proc_macro2::TokenStream::from(input).into_iter();
+    let runner: syn::DeriveInput = match tokens.next() {
+        Some(TokenTree::Group(group)) => syn::parse2(group.stream()).unwrap(),
+        _ => panic!("{}", TEMPLATE_USAGE),
+    };
+
+    // the rest are the arguments given to zapper_template!, separated by commas
+    let mut args = vec![vec![]];
+    for token in tokens {
+        match token {
+            TokenTree::Op(ref op) if op.op() == ',' => args.push(vec![]),
+            token => args.last_mut().unwrap().push(token),
+        }
+    }
+    if args.len() > 1 && args.last().unwrap().is_empty() {
+        args.pop();
+    }
+    let mut args = args
+        .into_iter()
+        .map(|arg| arg.into_iter().collect::<proc_macro2::TokenStream>());
+    let (env, source) = match (args.next(), args.next(), args.next(), args.next()) {
+        (Some(_), Some(source), None, None) => (None, source),
+        (Some(_), Some(env), Some(source), None) => (Some(env), source),
+        _ => panic!("{}", TEMPLATE_USAGE),
+    };
+    let env = env.map(|env| syn::parse2::<Type>(env).expect(TEMPLATE_USAGE));
+    let source = syn::parse2::<syn::LitStr>(source).expect(TEMPLATE_USAGE);
+
+    let Analysis {
+        filters,
+        num_fields,
+        str_fields,
+        ..
+    } = Analysis::from(&runner);
+    let name = runner.ident;
+    let fields = template::Fields {
+        runner: name,
+        str_enum: Ident::new(&(name.to_string() + "Strs"), name.span()),
+        num_fields: &num_fields,
+        str_fields: &str_fields,
+        filters: &filters,
+    };
+
+    let gen = template::template_fn(&source.value(), env.as_ref(), &fields, source.span());
+    gen.into()
+}
@@ -0,0 +1,444 @@
+// compiles a template into a plain Rust render method on the runner at build time. The template goes through the
+// same tokenizer, parser and optimizer that `zapper::compile` uses, then each expression is lowered straight to
+// Rust, so variables become field accesses and filters become direct calls to the filter functions.
+//
+// Templates are either declared with `#[zapper(...)]` on a `#[derive(ZapperRunner)]` struct, or compiled where
+// they're used with `zapper_template!(Runner, "...")`. A function-like macro only sees its own arguments, so the
+// derive also defines a `macro_rules!` macro for each runner which hands the struct's definition over, and the
+// template is lowered against the same fields either way. The values of environment constants aren't known until
+// the program runs, so instead of being folded in like `zapper::compile` does, they're read from the environment
+// passed to the render function.
+
+use proc_macro2::Span;
+use quote::Tokens;
+use std::borrow::Cow;
+use syn::{Ident, Type};
+use zapper_front::ast::{self, Expr, Literal, Numeric};
+use zapper_front::optimizer;
+use zapper_front::tokenizer::{Operator, Tokenizer};
+use zapper_front::{Environment, FilterInput};
+
+pub struct Template {
+    pub render_fn: Ident,
+    pub source: TemplateSource,
+    /// the environment's type, if the template can use its constants
+    pub env: Option<Type>,
+    pub span: Span,
+}
+
+pub enum TemplateSource {
+    /// a path relative to the crate's Cargo.toml
+    Path(String),
+    Inline(String),
+}
+
+enum FilterKind {
+    Numeric,
+    Stringified,
+    Custom,
+}
+
+struct Filter {
+    name: String,
+    arg_count: usize,
+    kind: FilterKind,
+    // pure filter functions don't take the runner
+    pure: bool,
+}
+
+/// What the template can see of the runner it is compiled for.
+pub struct Fields<'f> {
+    pub runner: Ident,
+    pub str_enum: Ident,
+    pub num_fields: &'f [Ident],
+    pub str_fields: &'f [(Ident, bool)],
+    pub filters: &'f [String],
+}
+
+// everything an expression in the template can refer to
+struct Scope<'s, 'f: 's> {
+    fields: &'s Fields<'f>,
+    filters: Vec<Filter>,
+    // whether the render method takes the environment
+    env: bool,
+    span: Span,
+    // how the generated code refers to the runner
+    this: Tokens,
+}
+
+// the optimizer runs without an environment, so nothing is ever a constant and filters can't be run at build time
+struct NoConstants;
+
+impl<'a> Environment<'a, (), (), ()> for NoConstants {
+    fn num_constant(&self, _: &str) -> Option<f64> {
+        None
+    }
+
+    fn str_constant(&self, _: &str) -> Option<Cow<'a, str>> {
+        None
+    }
+
+    fn num_var(_: &str) -> Option<()> {
+        None
+    }
+
+    fn str_var(_: &str) -> Option<()> {
+        None
+    }
+
+    fn filter(_: &str) -> Option<((), usize, FilterInput<()>, bool)> {
+        None
+    }
+}
+
+/// Generates the render method for `template`. Any error in the template becomes a `compile_error!` pointing at
+/// the attribute which declared it.
+pub fn impl_template(template: &Template, fields: &Fields) -> Tokens {
+    match render_fn(template, fields) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            let message = format!("error compiling template {}: {}", template.render_fn, err);
+            quote_spanned! { template.span=>
+                compile_error!(#message);
+            }
+        }
+    }
+}
+
+fn render_fn(template: &Template, fields: &Fields) -> Result<Tokens, String> {
+    let (source, dependency) = match template.source {
+        TemplateSource::Inline(ref source) => (source.clone(), None),
+        TemplateSource::Path(ref path) => {
+            let dir = ::std::env::var("CARGO_MANIFEST_DIR").map_err(|err| err.to_string())?;
+            let full_path = ::std::path::Path::new(&dir).join(path);
+            let source = ::std::fs::read_to_string(&full_path)
+                .map_err(|err| format!("could not read {}: {}", full_path.display(), err))?;
+            (source, Some(full_path.to_string_lossy().into_owned()))
+        }
+    };
+
+    let scope = Scope {
+        fields,
+        filters: fields.filters.iter().map(|f| parse_filter(f)).collect(),
+        env: template.env.is_some(),
+        span: template.span,
+        this: quote! { self },
+    };
+    let stmts = lower_template(&source, &scope)?;
+
+    // including the file makes cargo rebuild the crate whenever the template changes
+    let dependency = dependency.map(|path| quote! { let _ = include_str!(#path); });
+
+    let env = template.env.as_ref().map(|env| quote! { env: &#env, });
+
+    let runner = fields.runner;
+    let render_fn = template.render_fn;
+    Ok(quote! {
+        impl #runner {
+            #[allow(unused_mut, unused_variables, unused_parens)]
+            fn #render_fn<Writer: ::std::io::Write + ?Sized>(
+                &self,
+                #env
+                output: &mut Writer,
+            ) -> ::std::io::Result<()> {
+                #dependency
+                let mut buffer = String::new();
+                #(#stmts)*
+                Ok(())
+            }
+        }
+    })
+}
+
+/// Generates the render function for `zapper_template!`, which takes the runner as its first argument. Like
+/// `impl_template`, an error in the template becomes a `compile_error!`, pointing at the template's source.
+pub fn template_fn(source: &str, env: Option<&Type>, fields: &Fields, span: Span) -> Tokens {
+    let scope = Scope {
+        fields,
+        filters: fields.filters.iter().map(|f| parse_filter(f)).collect(),
+        env: env.is_some(),
+        span,
+        this: quote! { runner },
+    };
+    let stmts = match lower_template(source, &scope) {
+        Ok(stmts) => stmts,
+        Err(err) => {
+            let message = format!("error compiling template: {}", err);
+            return quote_spanned! { span=>
+                compile_error!(#message)
+            };
+        }
+    };
+
+    let env = env.map(|env| quote! { env: &#env, });
+
+    let runner = fields.runner;
+    quote! {
+        {
+            #[allow(unused_mut, unused_variables, unused_parens)]
+            fn render<Writer: ::std::io::Write + ?Sized>(
+                runner: &#runner,
+                #env
+                output: &mut Writer,
+            ) -> ::std::io::Result<()> {
+                let mut buffer = String::new();
+                #(#stmts)*
+                Ok(())
+            }
+            render
+        }
+    }
+}
+
+fn lower_template(source: &str, scope: &Scope) -> Result<Vec<Tokens>, String> {
+    let ast = ast::parse(Tokenizer::new(source))?;
+    let ast = optimizer::optimize(ast, &NoConstants);
+    ast.into_iter()
+        .map(|tree| lower_tree(tree, scope))
+        .collect()
+}
+
+fn parse_filter(filter: &str) -> Filter {
+    let pure = filter.ends_with("/pure");
+    let filter = if pure {
+        &filter[..filter.len() - "/pure".len()]
+    } else {
+        filter
+    };
+    let split = filter
+        .find('/')
+        .expect("filters must specify number of args and return type.");
+    Filter {
+        name: filter[..split].to_string(),
+        arg_count: filter[split + 1..filter.len() - 1]
+            .parse::<usize>()
+            .expect("argument count for filter must be a usize"),
+        kind: match filter.as_bytes()[filter.len() - 1] {
+            b'n' => FilterKind::Numeric,
+            b's' => FilterKind::Stringified,
+            _ => FilterKind::Custom,
+        },
+        pure,
+    }
+}
+
+fn lower_tree(tree: Expr, scope: &Scope) -> Result<Tokens, String> {
+    let this = &scope.this;
+    Ok(match tree {
+        Expr::Raw(string) => quote! { output.write_all(#string.as_bytes())?; },
+        Expr::StringLiteral(string) => {
+            let string = &*string;
+            quote! { output.write_all(#string.as_bytes())?; }
+        }
+        Expr::Identifier(id) => {
+            if let Some(field) = num_field(scope, id) {
+                quote! { write!(output, "{}", #this.#field as f64)?; }
+            } else if let Some(&(field, prim)) = str_field(scope, id) {
+                if prim {
+                    quote! { output.write_all(#this.#field.as_bytes())?; }
+                } else {
+                    quote! { write!(output, "{}", #this.#field)?; }
+                }
+            } else if let Some(constant) = constant(scope, id) {
+                quote! { write!(output, "{}", #constant)?; }
+            } else {
+                return Err(format!("Unknown identifier {:?}", id));
+            }
+        }
+        Expr::Numeric(numeric) => {
+            let value = lower_numeric(numeric, scope)?;
+            quote! { write!(output, "{}", #value)?; }
+        }
+        Expr::Filter(id, expr, args) => lower_filter(id, *expr, args, scope)?,
+        Expr::Include(name) => {
+            return Err(format!(
+                "partials are not supported by the derive, found an include of {:?}",
+                name
+            ))
+        }
+        Expr::Extends(name) => {
+            return Err(format!(
+                "template inheritance is not supported by the derive, found an extends of {:?}",
+                name
+            ))
+        }
+        // there is nothing to override a block, so it just renders its contents
+        Expr::Block(_) | Expr::EndBlock => quote!{},
+    })
+}
+
+fn lower_numeric(numeric: Numeric, scope: &Scope) -> Result<Tokens, String> {
+    let this = &scope.this;
+    Ok(match numeric {
+        Numeric::Raw(val) => lower_f64(val),
+        Numeric::Identifier(id) => {
+            if let Some(field) = num_field(scope, id) {
+                quote! { (#this.#field as f64) }
+            } else if str_field(scope, id).is_some() {
+                return Err(format!("{:?} is a string, numeric value was expected!", id));
+            } else if let Some(constant) = constant(scope, id) {
+                quote! { (#constant as f64) }
+            } else {
+                return Err(format!("Unknown identifier {:?}", id));
+            }
+        }
+        Numeric::Parentheses(expr) => lower_numeric(*expr, scope)?,
+        Numeric::Negate(expr) => {
+            let expr = lower_numeric(*expr, scope)?;
+            quote! { (-#expr) }
+        }
+        Numeric::Binary(op, left, right) => {
+            let left = lower_numeric(*left, scope)?;
+            let right = lower_numeric(*right, scope)?;
+            match op {
+                Operator::Plus => quote! { (#left + #right) },
+                Operator::Dash => quote! { (#left - #right) },
+                Operator::Slash => quote! { (#left / #right) },
+                Operator::Asterisk => quote! { (#left * #right) },
+                _ => unreachable!(),
+            }
+        }
+    })
+}
+
+// constant folding can produce values which can't be written as a plain literal
+fn lower_f64(val: f64) -> Tokens {
+    if val.is_nan() {
+        quote! { ::std::f64::NAN }
+    } else if val.is_infinite() && val > 0.0 {
+        quote! { ::std::f64::INFINITY }
+    } else if val.is_infinite() {
+        quote! { ::std::f64::NEG_INFINITY }
+    } else if val.is_sign_negative() {
+        let val = -val;
+        quote! { (-#val) }
+    } else {
+        quote! { #val }
+    }
+}
+
+fn lower_filter(id: &str, expr: Expr, args: Vec<Literal>, scope: &Scope) -> Result<Tokens, String> {
+    let filter = scope
+        .filters
+        .iter()
+        .find(|filter| filter.name == id)
+        .ok_or_else(|| format!("Unknown filter named {}", id))?;
+    if filter.arg_count != args.len() {
+        return Err(format!(
+            "filter {} expected {} args, but {} were provided",
+            id,
+            filter.arg_count,
+            args.len()
+        ));
+    }
+
+    let args = args
+        .into_iter()
+        .map(|arg| match arg {
+            Literal::Number(val) => Ok(lower_f64(val)),
+            Literal::StringLiteral(_) => {
+                Err("Filters can only be passed numeric arguments for now!".to_string())
+            }
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let args = quote! { &[#(#args),*] };
+    let filter_fn = Ident::new(id, Span::call_site());
+    let this = &scope.this;
+    let runner = if filter.pure {
+        quote!{}
+    } else {
+        quote! { #this, }
+    };
+
+    Ok(match (&filter.kind, expr) {
+        (&FilterKind::Numeric, Expr::Numeric(expr)) => {
+            let input = lower_numeric(expr, scope)?;
+            quote! { write!(output, "{}", #filter_fn(#runner #args, #input))?; }
+        }
+        (&FilterKind::Numeric, Expr::Identifier(val_id))
+            if num_field(scope, val_id).is_some()
+                || (str_field(scope, val_id).is_none() && scope.env) =>
+        {
+            let input = lower_numeric(Numeric::Identifier(val_id), scope)?;
+            quote! { write!(output, "{}", #filter_fn(#runner #args, #input))?; }
+        }
+        (&FilterKind::Numeric, expr) => {
+            return Err(format!(
+                "filter {} expected numeric input expression, found {:#?}",
+                id, expr
+            ))
+        }
+        (&FilterKind::Stringified, Expr::Identifier(val_id)) => {
+            let input = if let Some(&(field, prim)) = str_field(scope, val_id) {
+                if prim {
+                    quote! { &#this.#field }
+                } else {
+                    quote! { &#this.#field.to_string() }
+                }
+            } else if let Some(field) = num_field(scope, val_id) {
+                quote! { &(#this.#field as f64).to_string() }
+            } else if let Some(constant) = constant(scope, val_id) {
+                quote! { &#constant.to_string() }
+            } else {
+                return Err(format!(
+                    "Unknown identifier {:?} used on filter {:?}",
+                    val_id, id
+                ));
+            };
+            call_str_filter(filter_fn, &runner, args, input)
+        }
+        (&FilterKind::Stringified, Expr::Numeric(expr)) => {
+            let input = lower_numeric(expr, scope)?;
+            call_str_filter(filter_fn, &runner, args, quote! { &#input.to_string() })
+        }
+        (&FilterKind::Stringified, Expr::Filter(..)) => {
+            return Err("Nested filters are not yet supported!".to_string())
+        }
+        (&FilterKind::Stringified, Expr::StringLiteral(string)) => {
+            return Err(format!(
+                "filters cannot accept a string literal as input for now. String: {:?} used on filter {:?}",
+                string, id
+            ))
+        }
+        (&FilterKind::Stringified, _) => unreachable!(),
+        (&FilterKind::Custom, Expr::Identifier(val_id)) if str_field(scope, val_id).is_some() => {
+            let str_enum = scope.fields.str_enum;
+            let variant = Ident::new(val_id, Span::call_site());
+            call_str_filter(filter_fn, &runner, args, quote! { #str_enum::#variant })
+        }
+        (&FilterKind::Custom, expr) => {
+            return Err(format!(
+                "filter {} expected just a string identifier as the input.\nErroneous expression found: {:#?}",
+                id, expr
+            ))
+        }
+    })
+}
+
+fn call_str_filter(filter_fn: Ident, runner: &Tokens, args: Tokens, input: Tokens) -> Tokens {
+    quote! {
+        buffer.clear();
+        #filter_fn(#runner #args, #input, &mut buffer);
+        output.write_all(buffer.as_bytes())?;
+    }
+}
+
+fn num_field<'f>(scope: &Scope<'_, 'f>, id: &str) -> Option<&'f Ident> {
+    scope.fields.num_fields.iter().find(|f| f.as_ref() == id)
+}
+
+fn str_field<'f>(scope: &Scope<'_, 'f>, id: &str) -> Option<&'f (Ident, bool)> {
+    scope.fields.str_fields.iter().find(|f| f.0.as_ref() == id)
+}
+
+// anything that isn't one of the runner's fields is read from the environment, if the render method takes it. The
+// environment needs a field with that name, like the ones `#[derive(ZapperEnv)]` turns into constants, so a
+// missing one is still a compile error.
+fn constant(scope: &Scope, id: &str) -> Option<Tokens> {
+    if scope.env {
+        let field = Ident::new(id, scope.span);
+        Some(quote! { env.#field })
+    } else {
+        None
+    }
+}
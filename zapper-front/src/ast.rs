@@ -1,5 +1,4 @@
 use std::borrow::Cow;
-use std::iter::Peekable;
 use tokenizer::{Operator, Token, Tokenizer};
 
 pub type Ident<'a> = &'a str;
@@ -11,6 +10,13 @@ pub enum Expr<'a> {
     StringLiteral(Cow<'a, str>),
     Identifier(Ident<'a>),
     Numeric(Numeric<'a>),
+    /// `{{> name}}`, replaced by the partial's own expressions before the template is compiled
+    Include(Ident<'a>),
+    /// `{{extends "name"}}`, along with the blocks below, is resolved into a single template before compiling
+    Extends(Ident<'a>),
+    /// `{{#block name}}`, the start of a block that runs until the matching `EndBlock`
+    Block(Ident<'a>),
+    EndBlock,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -28,14 +34,77 @@ pub enum Literal<'a> {
     StringLiteral(Cow<'a, str>),
 }
 
-type PeekTokenizer<'a> = Peekable<Tokenizer<'a>>;
+/// A byte range of the template source.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+// like `Peekable`, but it can still report how far into the source the tokenizer has got
+struct PeekTokenizer<'a> {
+    tokenizer: Tokenizer<'a>,
+    peeked: Option<Option<Result<Token<'a>, String>>>,
+}
+
+impl<'a> PeekTokenizer<'a> {
+    fn peek(&mut self) -> Option<&Result<Token<'a>, String>> {
+        let tokenizer = &mut self.tokenizer;
+        self.peeked.get_or_insert_with(|| tokenizer.next()).as_ref()
+    }
+
+    // only accurate between substitution blocks, where nothing has been peeked yet
+    fn offset(&self) -> usize {
+        debug_assert!(self.peeked.is_none());
+        self.tokenizer.offset()
+    }
+}
+
+impl<'a> Iterator for PeekTokenizer<'a> {
+    type Item = Result<Token<'a>, String>;
+
+    fn next(&mut self) -> Option<Result<Token<'a>, String>> {
+        match self.peeked.take() {
+            Some(peeked) => peeked,
+            None => self.tokenizer.next(),
+        }
+    }
+}
 
 pub fn parse<'a>(tokenizer: Tokenizer<'a>) -> Result<Vec<Expr<'a>>, String> {
-    let mut tokenizer = tokenizer.peekable();
+    Ok(parse_spanned(tokenizer)?
+        .into_iter()
+        .map(|(expr, _)| expr)
+        .collect())
+}
+
+/// Parses the template like `parse`, pairing each top level expression with the part of the template it came from.
+pub fn parse_spanned<'a>(tokenizer: Tokenizer<'a>) -> Result<Vec<(Expr<'a>, Span)>, String> {
+    let mut tokenizer = PeekTokenizer {
+        tokenizer,
+        peeked: None,
+    };
     let mut nodes = Vec::new();
     loop {
+        let start = tokenizer.offset();
         match Expr::parse_outer(&mut tokenizer) {
-            Ok(node) => nodes.push(node),
+            Ok(node) => nodes.push((
+                node,
+                Span {
+                    start,
+                    end: tokenizer.offset(),
+                },
+            )),
             Err(ref err) if err.is_empty() => return Ok(nodes),
             Err(err) => return Err(err),
         }
@@ -78,6 +147,29 @@ impl<'a> Expr<'a> {
     fn parse_outer(tokenizer: &mut PeekTokenizer<'a>) -> Result<Expr<'a>, String> {
         match next!(tokenizer, "") {
             Token::Raw(string) => Ok(Expr::Raw(string)),
+            Token::OpeningBrace
+                if matches!(
+                    tokenizer.peek(),
+                    Some(&Ok(Token::Include(_)))
+                        | Some(&Ok(Token::Extends(_)))
+                        | Some(&Ok(Token::Block(_)))
+                        | Some(&Ok(Token::EndBlock))
+                ) =>
+            {
+                let tag = match next!(tokenizer) {
+                    Token::Include(name) => Expr::Include(name),
+                    Token::Extends(name) => Expr::Extends(name),
+                    Token::Block(name) => Expr::Block(name),
+                    _ => Expr::EndBlock,
+                };
+                match next!(tokenizer, UNEXPECTED_EOB) {
+                    Token::ClosingBrace => Ok(tag),
+                    tok => Err(format!(
+                        "Error: Unexpectedly found {:?} after {:?}",
+                        tok, tag
+                    )),
+                }
+            }
             Token::OpeningBrace => {
                 let expr = Expr::parse(tokenizer);
                 if let Err(err) = expr {
@@ -149,7 +241,7 @@ impl<'a> Expr<'a> {
         match token {
             Token::Identifier(ident) => Ok(Expr::Identifier(ident)),
             Token::Number(num) => Ok(Expr::Numeric(Numeric::Raw(num))),
-            Token::StringLiteral(string) => Ok(Expr::StringLiteral(string.into())),
+            Token::StringLiteral(string) => Ok(Expr::StringLiteral(string)),
             token => Err(format!("Invalid token: {:?}", token)),
         }
     }
@@ -261,7 +353,7 @@ impl<'a> Numeric<'a> {
 impl<'a> Literal<'a> {
     fn parse(tokenizer: &mut PeekTokenizer<'a>) -> Result<Literal<'a>, String> {
         match next!(tokenizer) {
-            Token::StringLiteral(string) => Ok(Literal::StringLiteral(string.into())),
+            Token::StringLiteral(string) => Ok(Literal::StringLiteral(string)),
             Token::Number(num) => Ok(Literal::Number(num)),
             token => Err(format!("Illegal token {:?} found", token)),
         }
@@ -275,6 +367,17 @@ mod tests {
     use super::*;
     use tokenizer::Tokenizer;
 
+    #[test]
+    fn spans() {
+        let source = "Hi {{ name }}, you are {{age}}!";
+        let spans = parse_spanned(Tokenizer::new(source))
+            .unwrap()
+            .into_iter()
+            .map(|(_, span)| &source[span.start..span.end])
+            .collect::<Vec<_>>();
+        assert_eq!(spans, ["Hi ", "{{ name }}", ", you are ", "{{age}}", "!"]);
+    }
+
     #[test]
     fn arithmetic() {
         let source = r#"This is a test {{ 3 / 4 - (2 + 4) }} and even more!"#;
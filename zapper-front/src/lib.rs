@@ -0,0 +1,52 @@
+// the front end of the zapper template compiler: the tokenizer, parser and optimizer. This is its own crate so that
+// zapper and the templates compiled by zapper_derive parse and optimize exactly the same way. Use it through the
+// re-exports in zapper.
+
+pub mod ast;
+pub mod optimizer;
+pub mod tokenizer;
+
+use std::borrow::Cow;
+use std::fmt::Debug;
+
+pub enum FilterInput<StrEnum> {
+    Numeric,
+    StrEnumId(Vec<StrEnum>),
+    Stringified,
+}
+
+pub trait Environment<
+    'a,
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+>
+{
+    fn num_constant(&self, &str) -> Option<f64>;
+    fn str_constant(&'a self, &str) -> Option<Cow<'a, str>>;
+
+    fn num_var(&str) -> Option<NumEnum>;
+    fn str_var(&str) -> Option<StrEnum>;
+
+    // returns a FilterEnum, the number of arguments, the input data type, and whether the filter is pure
+    fn filter(&str) -> Option<(FilterEnum, usize, FilterInput<StrEnum>, bool)>;
+
+    /// Runs a pure numeric filter at compile time. A pure filter's output depends only on its arguments and input,
+    /// so when the input is constant the optimizer calls this once instead of emitting a call for every render.
+    /// Returning `None` leaves the call in place.
+    fn pure_filter_num(&self, _filter: FilterEnum, _args: &[f64], _input: f64) -> Option<f64> {
+        None
+    }
+
+    /// The stringified counterpart of `pure_filter_num`, writing the output into `buffer`. Returns `false` if the
+    /// filter could not be run, which leaves the call in place.
+    fn pure_filter_str(
+        &self,
+        _filter: FilterEnum,
+        _args: &[f64],
+        _input: &str,
+        _buffer: &mut String,
+    ) -> bool {
+        false
+    }
+}
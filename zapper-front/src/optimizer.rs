@@ -0,0 +1,559 @@
+use super::{Environment, FilterInput};
+use ast::*;
+use std::fmt::Debug;
+use tokenizer::Operator;
+
+/// How deep into each expression the constant folding pass is allowed to go.
+pub const DEFAULT_EFFORT: u32 = 20;
+
+/// A user-provided pass over the whole template. It gets every expression along with the part of the template it
+/// came from, and returns the rewritten template.
+pub type CustomPass = dyn for<'x> Fn(Vec<(Expr<'x>, Span)>) -> Vec<(Expr<'x>, Span)> + Send + Sync;
+
+/// A single pass over the template, run by `run_passes`.
+pub enum Pass {
+    /// folds constant arithmetic, simplifies numeric expressions, and evaluates pure filters on constant inputs
+    ConstantFolding,
+    /// merges neighbouring raw text and string literals into a single piece of static text
+    MergeStrings,
+    Custom(Box<CustomPass>),
+}
+
+impl Pass {
+    /// The passes `compile` runs: constant folding followed by string merging.
+    pub fn defaults() -> Vec<Pass> {
+        vec![Pass::ConstantFolding, Pass::MergeStrings]
+    }
+}
+
+#[allow(unused)]
+pub fn optimize<
+    'a,
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+>(
+    ast: Vec<Expr<'a>>,
+    env: &'a Env,
+) -> Vec<Expr<'a>> {
+    let ast = ast
+        .into_iter()
+        .map(|tree| (tree, Span::default()))
+        .collect();
+    optimize_spanned(ast, env)
+        .into_iter()
+        .map(|(tree, _)| tree)
+        .collect()
+}
+
+/// Optimizes the output of `ast::parse_spanned` with the default passes. Expressions which get merged together end
+/// up with a span covering all of them.
+pub fn optimize_spanned<
+    'a,
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+>(
+    ast: Vec<(Expr<'a>, Span)>,
+    env: &'a Env,
+) -> Vec<(Expr<'a>, Span)> {
+    run_passes(ast, env, &Pass::defaults(), DEFAULT_EFFORT)
+}
+
+/// Runs `passes` over the template in order. Environment constants are always substituted first, even with no
+/// passes at all, since the bytecode has no other way to get at them.
+pub fn run_passes<
+    'a,
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+>(
+    ast: Vec<(Expr<'a>, Span)>,
+    env: &'a Env,
+    passes: &[Pass],
+    effort: u32,
+) -> Vec<(Expr<'a>, Span)> {
+    let ast = ast
+        .into_iter()
+        .map(|(tree, span)| (resolve_constants(tree, env), span))
+        .collect();
+    passes.iter().fold(ast, |ast, pass| match *pass {
+        // the second round turns anything the first one folded down to a number into a string literal
+        Pass::ConstantFolding => ast
+            .into_iter()
+            .map(|(tree, span)| (optimize_tree(tree, env, effort), span))
+            .map(|(tree, span)| (optimize_tree(tree, env, effort), span))
+            .collect(),
+        Pass::MergeStrings => merge_strings(ast),
+        Pass::Custom(ref pass) => pass(ast),
+    })
+}
+
+fn merge_strings(ast: Vec<(Expr, Span)>) -> Vec<(Expr, Span)> {
+    ast.into_iter().fold(Vec::new(), |mut acc, (v, v_span)| {
+        if let Some((t, t_span)) = acc.pop() {
+            let span = t_span.to(v_span);
+            match (t, v) {
+                (Expr::Raw(raw_str), Expr::StringLiteral(lit)) => {
+                    acc.push((
+                        Expr::StringLiteral((raw_str.to_string() + &lit).into()),
+                        span,
+                    ));
+                }
+                (Expr::StringLiteral(lit1), Expr::StringLiteral(lit2)) => {
+                    acc.push((Expr::StringLiteral((lit1.to_string() + &lit2).into()), span));
+                }
+                (Expr::StringLiteral(lit), Expr::Raw(raw_str)) => {
+                    acc.push((
+                        Expr::StringLiteral((lit.to_string() + raw_str).into()),
+                        span,
+                    ));
+                }
+                (t, v) => {
+                    acc.push((t, t_span));
+                    acc.push((v, v_span));
+                }
+            }
+        } else {
+            acc.push((v, v_span));
+        }
+        acc
+    })
+}
+
+// replaces identifiers naming environment constants with their values, without simplifying anything else
+fn resolve_constants<
+    'a,
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+>(
+    tree: Expr<'a>,
+    env: &'a Env,
+) -> Expr<'a> {
+    match tree {
+        Expr::Identifier(id) => {
+            if let Some(val) = env.num_constant(id) {
+                Expr::Numeric(Numeric::Raw(val))
+            } else if let Some(val) = env.str_constant(id) {
+                Expr::StringLiteral(val)
+            } else {
+                Expr::Identifier(id)
+            }
+        }
+        Expr::Numeric(numeric) => Expr::Numeric(resolve_numeric(numeric, env)),
+        Expr::Filter(id, expr, args) => {
+            Expr::Filter(id, Box::new(resolve_constants(*expr, env)), args)
+        }
+        expr => expr,
+    }
+}
+
+fn resolve_numeric<
+    'a,
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+>(
+    numeric: Numeric<'a>,
+    env: &'a Env,
+) -> Numeric<'a> {
+    match numeric {
+        Numeric::Identifier(id) => match env.num_constant(id) {
+            Some(val) => Numeric::Raw(val),
+            None => Numeric::Identifier(id),
+        },
+        Numeric::Binary(op, left, right) => Numeric::Binary(
+            op,
+            Box::new(resolve_numeric(*left, env)),
+            Box::new(resolve_numeric(*right, env)),
+        ),
+        Numeric::Negate(expr) => Numeric::Negate(Box::new(resolve_numeric(*expr, env))),
+        Numeric::Parentheses(expr) => Numeric::Parentheses(Box::new(resolve_numeric(*expr, env))),
+        Numeric::Raw(raw) => Numeric::Raw(raw),
+    }
+}
+
+pub fn optimize_tree<
+    'a,
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+>(
+    tree: Expr<'a>,
+    env: &'a Env,
+    effort: u32,
+) -> Expr<'a> {
+    if effort == 0 {
+        return tree;
+    }
+    let effort = effort - 1;
+    match tree {
+        Expr::Identifier(id) => {
+            if let Some(val) = env.num_constant(id) {
+                Expr::Numeric(Numeric::Raw(val))
+            } else if let Some(val) = env.str_constant(id) {
+                Expr::StringLiteral(val)
+            } else {
+                Expr::Identifier(id)
+            }
+        }
+        Expr::Numeric(Numeric::Raw(val)) => Expr::StringLiteral(val.to_string().into()),
+        Expr::Numeric(numeric) => Expr::Numeric(optimize_numeric(numeric, env, effort)),
+        Expr::Filter(id, expr, args) => {
+            let expr = optimize_filter_input(*expr, env, effort);
+            match evaluate_pure_filter(id, &expr, &args, env) {
+                Some(output) => output,
+                None => Expr::Filter(id, Box::new(expr), args),
+            }
+        }
+        expr => expr,
+    }
+}
+
+// like optimize_tree, except constant numbers stay numeric, since the filter still has to be fed a number
+pub fn optimize_filter_input<
+    'a,
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+>(
+    expr: Expr<'a>,
+    env: &'a Env,
+    effort: u32,
+) -> Expr<'a> {
+    match expr {
+        Expr::Numeric(numeric) => Expr::Numeric(optimize_numeric(numeric, env, effort)),
+        Expr::Identifier(id) => match env.num_constant(id) {
+            Some(val) => Expr::Numeric(Numeric::Raw(val)),
+            None => optimize_tree(Expr::Identifier(id), env, effort),
+        },
+        expr => optimize_tree(expr, env, effort),
+    }
+}
+
+/// Runs a pure filter through the environment when its input and arguments are all constant, so the output is
+/// computed once here rather than on every render. Numeric filters fold into a number which later passes can keep
+/// folding, while stringified filters become a string literal.
+pub fn evaluate_pure_filter<
+    'a,
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+>(
+    id: &str,
+    input: &Expr<'a>,
+    args: &[Literal],
+    env: &'a Env,
+) -> Option<Expr<'a>> {
+    let (filter, arg_count, input_type, pure) = Env::filter(id)?;
+    if !pure || arg_count != args.len() {
+        return None;
+    }
+    let args = args
+        .iter()
+        .map(|arg| match *arg {
+            Literal::Number(val) => Some(val),
+            Literal::StringLiteral(_) => None,
+        })
+        .collect::<Option<Vec<f64>>>()?;
+
+    let mut buffer = String::new();
+    match (input_type, input) {
+        (FilterInput::Numeric, Expr::Numeric(Numeric::Raw(val))) => env
+            .pure_filter_num(filter, &args, *val)
+            .map(|output| Expr::Numeric(Numeric::Raw(output))),
+        (FilterInput::Stringified, Expr::Numeric(Numeric::Raw(val))) => {
+            if env.pure_filter_str(filter, &args, &val.to_string(), &mut buffer) {
+                Some(Expr::StringLiteral(buffer.into()))
+            } else {
+                None
+            }
+        }
+        (FilterInput::Stringified, Expr::StringLiteral(string)) => {
+            if env.pure_filter_str(filter, &args, string, &mut buffer) {
+                Some(Expr::StringLiteral(buffer.into()))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+pub fn optimize_numeric<
+    'a,
+    NumEnum: 'a + Send + Sync,
+    StrEnum: 'a + Send + Sync + Debug + PartialEq,
+    FilterEnum: 'a + Send + Sync,
+    Env: Environment<'a, NumEnum, StrEnum, FilterEnum>,
+>(
+    numeric: Numeric<'a>,
+    env: &'a Env,
+    effort: u32,
+) -> Numeric<'a> {
+    if effort == 0 {
+        return numeric;
+    }
+    let effort = effort - 1;
+    match numeric {
+        Numeric::Identifier(id) => {
+            if let Some(val) = env.num_constant(id) {
+                Numeric::Raw(val)
+            } else {
+                Numeric::Identifier(id)
+            }
+        }
+        Numeric::Binary(op, left, right) => {
+            let left = optimize_numeric(*left, env, effort);
+            let right = optimize_numeric(*right, env, effort);
+            simplify_binary(op, left, right)
+        }
+        Numeric::Negate(expr) => negate(optimize_numeric(*expr, env, effort)),
+        // the tree already captures the grouping, so parentheses never need to survive optimization
+        Numeric::Parentheses(expr) => optimize_numeric(*expr, env, effort),
+        Numeric::Raw(raw) => Numeric::Raw(raw),
+    }
+}
+
+// Every rewrite in here evaluates to exactly the same value as the original expression for every possible input,
+// so rendered output never changes. That rules out a few rewrites which are only true of real numbers:
+//
+// - `x + 0 => x`, because -0 + 0 is +0. Only adding -0 or subtracting +0 is an identity.
+// - `x * 0 => 0`, because NaN * 0 and infinity * 0 are NaN, and -2 * 0 is -0.
+// - `(x + 2) + 3 => x + 5`, because the two additions round separately. For x = 0.89 the original is
+//   5.890000000000001 but the rewrite is 5.89. Constants are only reassociated when each multiplication scales x up
+//   by a power of two, which is always exact.
+fn simplify_binary<'a>(op: Operator, left: Numeric<'a>, right: Numeric<'a>) -> Numeric<'a> {
+    use self::Numeric::*;
+    use tokenizer::Operator::*;
+
+    match (op, left, right) {
+        (Plus, Raw(left), Raw(right)) => Raw(left + right),
+        (Dash, Raw(left), Raw(right)) => Raw(left - right),
+        (Slash, Raw(left), Raw(right)) => Raw(left / right),
+        (Asterisk, Raw(left), Raw(right)) => Raw(left * right),
+
+        // NaN swallows anything it touches
+        (_, Raw(val), _) | (_, _, Raw(val)) if val.is_nan() => Raw(val),
+
+        // identities
+        (Asterisk, x, Raw(one)) | (Asterisk, Raw(one), x) | (Slash, x, Raw(one)) if one == 1.0 => x,
+        (Plus, x, Raw(zero)) | (Plus, Raw(zero), x) if is_neg_zero(zero) => x,
+        (Dash, x, Raw(zero)) if zero == 0.0 && zero.is_sign_positive() => x,
+
+        // negations in disguise
+        (Asterisk, x, Raw(neg_one)) | (Asterisk, Raw(neg_one), x) | (Slash, x, Raw(neg_one))
+            if neg_one == -1.0 =>
+        {
+            negate(x)
+        }
+        (Dash, Raw(zero), x) if is_neg_zero(zero) => negate(x),
+
+        // reassociation
+        (Asterisk, Binary(Asterisk, inner_left, inner_right), Raw(outer)) => {
+            reassociate_scale(*inner_left, *inner_right, outer, false)
+        }
+        (Asterisk, Raw(outer), Binary(Asterisk, inner_left, inner_right)) => {
+            reassociate_scale(*inner_left, *inner_right, outer, true)
+        }
+
+        (op, left, right) => Binary(op, Box::new(left), Box::new(right)),
+    }
+}
+
+// folds `outer` into the product `inner_left * inner_right` when that's exact
+fn reassociate_scale<'a>(
+    inner_left: Numeric<'a>,
+    inner_right: Numeric<'a>,
+    outer: f64,
+    outer_first: bool,
+) -> Numeric<'a> {
+    use self::Numeric::*;
+
+    match (inner_left, inner_right) {
+        (x, Raw(inner)) | (Raw(inner), x) if is_exact_scale(inner, outer) => Binary(
+            Operator::Asterisk,
+            Box::new(x),
+            Box::new(Raw(inner * outer)),
+        ),
+        (inner_left, inner_right) => {
+            let inner = Box::new(Binary(
+                Operator::Asterisk,
+                Box::new(inner_left),
+                Box::new(inner_right),
+            ));
+            let outer = Box::new(Raw(outer));
+            if outer_first {
+                Binary(Operator::Asterisk, outer, inner)
+            } else {
+                Binary(Operator::Asterisk, inner, outer)
+            }
+        }
+    }
+}
+
+fn negate(numeric: Numeric) -> Numeric {
+    match numeric {
+        Numeric::Raw(val) => Numeric::Raw(-val),
+        Numeric::Negate(expr) => *expr,
+        expr => Numeric::Negate(Box::new(expr)),
+    }
+}
+
+fn is_neg_zero(val: f64) -> bool {
+    val == 0.0 && val.is_sign_negative()
+}
+
+// multiplying by a power of two at least 1 in magnitude is exact, short of overflowing to infinity, and then it
+// overflows whichever way round the multiplications are done
+fn is_exact_scale(left: f64, right: f64) -> bool {
+    let is_scale =
+        |val: f64| val.is_finite() && val.abs() >= 1.0 && val.to_bits() & ((1 << 52) - 1) == 0;
+    is_scale(left) && is_scale(right) && (left * right).is_finite()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use std::f64;
+    use tokenizer::Tokenizer;
+
+    // an environment with a single constant, provider_code = 31
+    struct Constants;
+
+    impl<'a> Environment<'a, (), (), ()> for Constants {
+        fn num_constant(&self, name: &str) -> Option<f64> {
+            match name {
+                "provider_code" => Some(31.0),
+                _ => None,
+            }
+        }
+
+        fn str_constant(&self, _: &str) -> Option<Cow<'a, str>> {
+            None
+        }
+
+        fn num_var(_: &str) -> Option<()> {
+            None
+        }
+
+        fn str_var(_: &str) -> Option<()> {
+            None
+        }
+
+        fn filter(_: &str) -> Option<((), usize, FilterInput<()>, bool)> {
+            None
+        }
+    }
+
+    fn optimize(source: &'static str) -> Numeric<'static> {
+        // optimized trees can borrow from the environment
+        let env: &'static Constants = &Constants;
+        let mut ast = parse(Tokenizer::new(source)).unwrap();
+        match ast.pop() {
+            Some(Expr::Numeric(numeric)) => optimize_numeric(numeric, env, 20),
+            other => panic!("expected a numeric expression, found {:?}", other),
+        }
+    }
+
+    fn var(id: &'static str) -> Box<Numeric<'static>> {
+        Box::new(Numeric::Identifier(id))
+    }
+
+    #[test]
+    fn identities() {
+        assert_eq!(optimize("{{weight * 1}}"), Numeric::Identifier("weight"));
+        assert_eq!(optimize("{{1 * weight}}"), Numeric::Identifier("weight"));
+        assert_eq!(optimize("{{weight / 1}}"), Numeric::Identifier("weight"));
+        assert_eq!(optimize("{{weight - 0}}"), Numeric::Identifier("weight"));
+        // constants are folded in first, so they can make an identity too
+        assert_eq!(
+            optimize("{{weight * (provider_code - 30)}}"),
+            Numeric::Identifier("weight")
+        );
+    }
+
+    #[test]
+    fn keeps_non_identities() {
+        // -0 + 0 is +0, so adding zero changes the sign of a -0 weight
+        assert_eq!(
+            optimize("{{weight + 0}}"),
+            Numeric::Binary(Operator::Plus, var("weight"), Box::new(Numeric::Raw(0.0)))
+        );
+        assert!((-0.0f64 + 0.0).is_sign_positive());
+
+        // NaN * 0 is NaN and -2 * 0 is -0, so multiplying by zero doesn't always give zero
+        assert_eq!(
+            optimize("{{weight * 0}}"),
+            Numeric::Binary(
+                Operator::Asterisk,
+                var("weight"),
+                Box::new(Numeric::Raw(0.0))
+            )
+        );
+        assert!((f64::NAN * 0.0).is_nan());
+        assert!((-2.0f64 * 0.0).is_sign_negative());
+
+        // the additions round differently once they're reassociated
+        assert_eq!((0.89 + 2.0) + 3.0, 5.890000000000001);
+        assert_eq!(0.89 + 5.0, 5.89);
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(optimize("{{-(-weight)}}"), Numeric::Identifier("weight"));
+        assert_eq!(optimize("{{weight * -1}}"), Numeric::Negate(var("weight")));
+        assert_eq!(optimize("{{-weight / -1}}"), Numeric::Identifier("weight"));
+    }
+
+    #[test]
+    fn nan_absorbs() {
+        match optimize("{{weight + 0 / 0}}") {
+            Numeric::Raw(val) => assert!(val.is_nan()),
+            other => panic!("expected NaN, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parentheses() {
+        assert_eq!(
+            optimize("{{weight * (age)}}"),
+            Numeric::Binary(Operator::Asterisk, var("weight"), var("age"))
+        );
+    }
+
+    #[test]
+    fn reassociation() {
+        assert_eq!(
+            optimize("{{2 * weight * 4}}"),
+            Numeric::Binary(
+                Operator::Asterisk,
+                var("weight"),
+                Box::new(Numeric::Raw(8.0))
+            )
+        );
+        // 0.5 scales down, where the two multiplications could round a subnormal twice
+        assert_eq!(
+            optimize("{{0.5 * weight * 4}}"),
+            Numeric::Binary(
+                Operator::Asterisk,
+                Box::new(Numeric::Raw(0.5)),
+                Box::new(Numeric::Binary(
+                    Operator::Asterisk,
+                    var("weight"),
+                    Box::new(Numeric::Raw(4.0))
+                )),
+            )
+        );
+    }
+}
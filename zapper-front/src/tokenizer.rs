@@ -0,0 +1,836 @@
+use std::borrow::Cow;
+use std::mem;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token<'a> {
+    OpeningBrace,
+    ClosingBrace,
+    Op(Operator),
+    Number(f64),
+    /// only owned when the literal contained escape sequences
+    StringLiteral(Cow<'a, str>),
+    Identifier(&'a str),
+    Raw(&'a str),
+    /// `> name`, which includes the partial template with that name
+    Include(&'a str),
+    /// `extends "name"`, which makes this template a child of the named one
+    Extends(&'a str),
+    /// `#block name`, which opens a block that child templates can override
+    Block(&'a str),
+    /// `/block`, which closes the innermost open block
+    EndBlock,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operator {
+    Plus,
+    Dash,
+    Slash,
+    Asterisk,
+    OpeningParen,
+    ClosingParen,
+    Pipe,
+}
+
+impl Operator {
+    pub fn value(&self) -> u32 {
+        use self::Operator::*;
+        match *self {
+            Pipe => 0,
+            Plus => 1,
+            Dash => 2,
+            Asterisk => 3,
+            Slash => 4,
+            OpeningParen => 5,
+            ClosingParen => 5,
+        }
+    }
+}
+
+/// Settings that change how a template is tokenized.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Syntax {
+    /// Removes the newline after a tag that doesn't print anything itself, such as `{{#block name}}`,
+    /// `{{> partial}}` or a `{{! comment }}`, along with any indentation before it on its line.
+    pub trim_blocks: bool,
+    // the delimiters around a substitution block, which are only ever set through `Syntax::new`
+    open: String,
+    close: String,
+}
+
+impl Syntax {
+    /// Uses `open` and `close` around substitution blocks instead of `{{` and `}}`. Neither can be empty or contain
+    /// whitespace. A template can also switch to other delimiters partway through with `{{=<% %>=}}`.
+    pub fn new(open: &str, close: &str) -> Result<Syntax, String> {
+        for delimiter in &[open, close] {
+            if delimiter.is_empty() || delimiter.contains(char::is_whitespace) {
+                return Err(format!("Invalid delimiter {:?}", delimiter));
+            }
+        }
+        Ok(Syntax {
+            trim_blocks: false,
+            open: open.to_string(),
+            close: close.to_string(),
+        })
+    }
+}
+
+impl Default for Syntax {
+    fn default() -> Syntax {
+        Syntax {
+            trim_blocks: false,
+            open: "{{".to_string(),
+            close: "}}".to_string(),
+        }
+    }
+}
+
+/// Splits a template into tokens. Outside of a substitution block, `\{{` prints the opening delimiter instead of
+/// opening a block, and `\\{{` prints a single backslash before a block.
+///
+/// Backslashes used to be printed as they were, so a template which printed a backslash right before a block, such
+/// as `C:\{{dir}}`, now has to double it as `C:\\{{dir}}`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tokenizer<'a> {
+    template: &'a str,
+    source: &'a str,
+    len: usize,
+    in_template: bool,
+    syntax: Syntax,
+    // whether the substitution block being tokenized is a tag that doesn't print anything
+    in_statement: bool,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(source: &'a str) -> Tokenizer<'a> {
+        Tokenizer::with_syntax(source, Syntax::default())
+    }
+
+    pub fn with_syntax(source: &'a str, syntax: Syntax) -> Tokenizer<'a> {
+        Tokenizer {
+            template: source,
+            source,
+            len: source.len(),
+            in_template: false,
+            syntax,
+            in_statement: false,
+        }
+    }
+
+    /// The byte offset into the template of the next token.
+    pub fn offset(&self) -> usize {
+        self.len - self.source.len()
+    }
+
+    // comments are dropped entirely, so the raw text on either side of them can be merged later on. `{{!-- --}}`
+    // comments can contain `}}`, but `{{! }}` comments end at the first one.
+    fn skip_comment(&mut self) -> Result<(), String> {
+        let rest = &self.source[self.syntax.open.len() + 1..];
+        let (rest, close) = match rest.strip_prefix("--") {
+            Some(rest) => (rest, format!("--{}", self.syntax.close)),
+            None => (rest, self.syntax.close.clone()),
+        };
+
+        match rest.find(&*close) {
+            Some(end) => {
+                self.source = &rest[end + close.len()..];
+                self.trim_block();
+                Ok(())
+            }
+            None => {
+                self.source = "";
+                Err("Unclosed comment".to_string())
+            }
+        }
+    }
+
+    // the tags around a verbatim section, which are `{{{{raw}}}}` and `{{{{/raw}}}}` with the default delimiters
+    fn verbatim_tags(&self) -> (String, String) {
+        let (open, close) = (&self.syntax.open, &self.syntax.close);
+        (
+            format!("{0}{0}raw{1}{1}", open, close),
+            format!("{0}{0}/raw{1}{1}", open, close),
+        )
+    }
+
+    // everything in a verbatim section is printed as it is, even if it looks like a template. An empty section
+    // produces no token at all.
+    fn verbatim(&mut self, raw_open: &str, raw_close: &str) -> Option<Result<Token<'a>, String>> {
+        let rest = &self.source[raw_open.len()..];
+        match rest.find(raw_close) {
+            Some(0) => {
+                self.source = &rest[raw_close.len()..];
+                None
+            }
+            Some(end) => {
+                self.source = &rest[end + raw_close.len()..];
+                Some(Ok(Token::Raw(&rest[..end])))
+            }
+            None => {
+                self.source = "";
+                Some(Err(format!(
+                    "No {} to end the {} block",
+                    raw_close, raw_open
+                )))
+            }
+        }
+    }
+
+    // `{{=<% %>=}}` switches to `<%` and `%>` as the delimiters for the rest of the template
+    fn switch_delimiters(&mut self) -> Result<(), String> {
+        let rest = &self.source[self.syntax.open.len() + 1..];
+        let end = match rest.find(&format!("={}", self.syntax.close)) {
+            Some(end) => end,
+            None => {
+                self.source = "";
+                return Err("Unclosed delimiter switch".to_string());
+            }
+        };
+
+        let delimiters = rest[..end].split_whitespace().collect::<Vec<_>>();
+        if delimiters.len() != 2 {
+            self.source = "";
+            return Err(format!(
+                "Expected an opening and closing delimiter to switch to, found {:?}",
+                &rest[..end]
+            ));
+        }
+        self.source = &rest[end + 1 + self.syntax.close.len()..];
+        self.syntax.open = delimiters[0].to_string();
+        self.syntax.close = delimiters[1].to_string();
+        self.trim_block();
+        Ok(())
+    }
+
+    // reads a string literal up to its closing quote, starting just after the opening one. The string is only copied
+    // if it has escape sequences to replace.
+    fn string_literal(&mut self) -> Result<Cow<'a, str>, String> {
+        let source = self.source;
+        let mut unescaped: Option<String> = None;
+        let (mut start, mut pos) = (0, 0);
+        while let Some(c) = source[pos..].chars().next() {
+            match c {
+                '"' => {
+                    self.source = &source[pos + 1..];
+                    return Ok(match unescaped {
+                        Some(mut string) => {
+                            string.push_str(&source[start..pos]);
+                            Cow::Owned(string)
+                        }
+                        None => Cow::Borrowed(&source[..pos]),
+                    });
+                }
+                '\\' => {
+                    let (escaped, len) = match unescape(&source[pos + 1..]) {
+                        Ok(escaped) => escaped,
+                        Err(err) => {
+                            self.source = "";
+                            return Err(err);
+                        }
+                    };
+                    let string = unescaped.get_or_insert_with(String::new);
+                    string.push_str(&source[start..pos]);
+                    string.push(escaped);
+                    pos += 1 + len;
+                    start = pos;
+                }
+                c => pos += c.len_utf8(),
+            }
+        }
+
+        self.source = "";
+        Err("No closing quotation mark".to_string())
+    }
+
+    // in trim_blocks mode, removes the newline after a tag that doesn't print anything
+    fn trim_block(&mut self) {
+        if !self.syntax.trim_blocks {
+            return;
+        }
+        if self.source.starts_with("\r\n") {
+            self.source = &self.source[2..];
+        } else if self.source.starts_with('\n') {
+            self.source = &self.source[1..];
+        }
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Result<Token<'a>, String>;
+
+    fn next(&mut self) -> Option<Result<Token<'a>, String>> {
+        use self::Operator::*;
+        use self::Token::*;
+
+        // comments, delimiter switches and empty text produce no token, so the loop moves on to the next one
+        loop {
+            if self.source.len() == 0 {
+                return None;
+            }
+
+            if !self.in_template {
+                let open = self.syntax.open.len();
+                let index = self
+                    .source
+                    .find(&*self.syntax.open)
+                    .unwrap_or_else(|| self.source.len());
+                if index == 0 && self.source[open..].starts_with('!') {
+                    if let Err(err) = self.skip_comment() {
+                        return Some(Err(err));
+                    }
+                    continue;
+                }
+                if index == 0 && self.source[open..].starts_with('=') {
+                    if let Err(err) = self.switch_delimiters() {
+                        return Some(Err(err));
+                    }
+                    continue;
+                }
+                if index == 0 && self.source[open..].starts_with(&*self.syntax.open) {
+                    let (raw_open, raw_close) = self.verbatim_tags();
+                    if self.source.starts_with(&raw_open) {
+                        match self.verbatim(&raw_open, &raw_close) {
+                            Some(token) => return Some(token),
+                            None => continue,
+                        }
+                    }
+                }
+                if index < self.source.len() && self.source[..index].ends_with("\\\\") {
+                    // `\\{{` prints one backslash, and the block opens as usual
+                    let next = &self.source[..index - 1];
+                    self.source = &self.source[index..];
+                    return Some(Ok(Raw(next)));
+                }
+                if index < self.source.len() && self.source[..index].ends_with('\\') {
+                    // `\{{` prints the braces instead of opening a substitution block
+                    if index == 1 {
+                        let braces = &self.source[1..1 + open];
+                        self.source = &self.source[1 + open..];
+                        return Some(Ok(Raw(braces)));
+                    }
+                    let (next, source) = self.source.split_at(index - 1);
+                    self.source = source;
+                    return Some(Ok(Raw(next)));
+                }
+                if index == 0 {
+                    // skip the opening curly braces, along with a trim marker. What it trims was done with the raw text.
+                    self.source = &self.source[open..];
+                    if trims_left(self.source) {
+                        self.source = &self.source[1..];
+                    }
+                    self.in_template = true;
+                    return Some(Ok(OpeningBrace));
+                } else {
+                    // return a chunk of raw text
+                    let offset = self.offset();
+                    let at_start = offset == 0 || self.template[..offset].ends_with('\n');
+                    let (mut next, source) = self.source.split_at(index);
+                    self.source = source;
+                    if let Some(tag) = source.get(open..) {
+                        if trims_left(tag) {
+                            next = next.trim_end();
+                        } else if self.syntax.trim_blocks && is_statement(tag) {
+                            next = strip_indent(next, at_start);
+                        }
+                    }
+                    if next.is_empty() {
+                        continue;
+                    }
+                    return Some(Ok(Raw(next)));
+                }
+            } else {
+                let word = self.source.split_whitespace().next()?;
+
+                // whitespace-aware starting position
+                let word_start = self.source.find(word.chars().next().unwrap()).unwrap();
+                self.source = &self.source[word_start..];
+
+                // a string can contain anything, including whitespace and the closing delimiter
+                if self.source.starts_with('"') {
+                    self.source = &self.source[1..];
+                    return Some(self.string_literal().map(StringLiteral));
+                }
+
+                let word_len = word.len();
+
+                let close = &*self.syntax.close;
+                let mut end = self.source[..word_len].find(close).unwrap_or(word_len);
+
+                if end == 0 {
+                    self.source = &self.source[close.len()..];
+                    self.in_template = false;
+                    if mem::replace(&mut self.in_statement, false) {
+                        self.trim_block();
+                    }
+                    return Some(Ok(ClosingBrace));
+                }
+
+                // an expression can never end in a `-`, so this is always a trim marker
+                if self.source.starts_with('-') && self.source[1..].starts_with(close) {
+                    self.source = self.source[1 + close.len()..].trim_start();
+                    self.in_template = false;
+                    self.in_statement = false;
+                    return Some(Ok(ClosingBrace));
+                }
+
+                if self.source.starts_with('>') {
+                    let (name, source) = split_name(&self.source[1..], close);
+                    self.source = source;
+                    if name.is_empty() {
+                        return Some(Err("Expected the name of a partial after >".to_string()));
+                    }
+                    self.in_statement = true;
+                    return Some(Ok(Include(name)));
+                }
+
+                if self.source.starts_with('#') {
+                    let (tag, source) = split_name(&self.source[1..], close);
+                    let (name, source) = split_name(source, close);
+                    self.source = source;
+                    self.in_statement = true;
+                    return Some(match tag {
+                        "block" if !name.is_empty() => Ok(Block(name)),
+                        "block" => Err("Expected the name of a block after #block".to_string()),
+                        tag => Err(format!("Unknown tag #{}", tag)),
+                    });
+                }
+
+                if &self.source[..end] == "/block" {
+                    self.source = &self.source[end..];
+                    self.in_statement = true;
+                    return Some(Ok(EndBlock));
+                }
+
+                if &self.source[..end] == "extends"
+                    && self.source[end..].trim_start().starts_with('"')
+                {
+                    let rest = self.source[end..].trim_start();
+                    return Some(match rest[1..].find('"') {
+                        Some(quote) => {
+                            self.source = &rest[quote + 2..];
+                            self.in_statement = true;
+                            Ok(Extends(&rest[1..quote + 1]))
+                        }
+                        None => {
+                            self.source = "";
+                            Err("No closing quotation mark".to_string())
+                        }
+                    });
+                }
+
+                if let Some(operator) =
+                    self.source[..end].find(&['|', '*', '+', '-', '/', '(', ')', '"'] as &[char])
+                {
+                    if operator == 0 {
+                        let op = &self.source[0..1];
+                        self.source = &self.source[1..];
+                        return Some(match op {
+                            "+" => Ok(Op(Plus)),
+                            "-" => Ok(Op(Dash)),
+                            "/" => Ok(Op(Slash)),
+                            "*" => Ok(Op(Asterisk)),
+                            "|" => Ok(Op(Pipe)),
+                            "(" => Ok(Op(OpeningParen)),
+                            ")" => Ok(Op(ClosingParen)),
+                            op => Err(format!("invalid operator {}", op)),
+                        });
+                    }
+                    end = operator;
+                }
+
+                let word = &word[..end];
+                self.source = &self.source[end..];
+
+                if let Ok(num) = word[..end].parse() {
+                    return Some(Ok(Number(num)));
+                }
+
+                return Some(Ok(Identifier(word)));
+            }
+        }
+    }
+}
+
+// the character an escape sequence stands for, starting just after its backslash, along with the length of the rest
+// of the sequence
+fn unescape(sequence: &str) -> Result<(char, usize), String> {
+    let escaped = match sequence.chars().next() {
+        Some('n') => '\n',
+        Some('t') => '\t',
+        Some('r') => '\r',
+        Some('0') => '\0',
+        Some('\\') => '\\',
+        Some('"') => '"',
+        Some('\'') => '\'',
+        Some('u') => {
+            // `\u{..}` with one to six hex digits, like in Rust
+            let hex = sequence[1..]
+                .strip_prefix('{')
+                .and_then(|rest| rest.find('}').map(|end| &rest[..end]))
+                .filter(|hex| {
+                    !hex.is_empty() && hex.len() <= 6 && hex.chars().all(|c| c.is_ascii_hexdigit())
+                });
+            return match hex {
+                Some(hex) => match ::std::char::from_u32(u32::from_str_radix(hex, 16).unwrap()) {
+                    Some(c) => Ok((c, hex.len() + 3)),
+                    None => Err(format!(
+                        "Invalid unicode escape \\u{{{}}} in string literal",
+                        hex
+                    )),
+                },
+                None => {
+                    Err("Expected \\u{..} with one to six hex digits in string literal".to_string())
+                }
+            };
+        }
+        Some(c) => return Err(format!("Unknown escape sequence \\{} in string literal", c)),
+        None => return Err("No closing quotation mark".to_string()),
+    };
+    Ok((escaped, 1))
+}
+
+// `{{-` followed by whitespace trims the whitespace before it, anything else is a negative number
+fn trims_left(tag: &str) -> bool {
+    tag.starts_with('-') && tag[1..].starts_with(char::is_whitespace)
+}
+
+// whether a tag, starting just after its opening braces, doesn't print anything itself
+fn is_statement(tag: &str) -> bool {
+    if tag.starts_with('!') || tag.starts_with('=') {
+        return true;
+    }
+    let tag = tag.trim_start();
+    if tag.starts_with('>') || tag.starts_with('#') || tag.starts_with("/block") {
+        return true;
+    }
+    tag.starts_with("extends") && tag["extends".len()..].trim_start().starts_with('"')
+}
+
+// removes the spaces and tabs at the end of `raw`, if that's all there is on its last line. `at_start` says whether
+// `raw` starts at the beginning of a line.
+fn strip_indent(raw: &str, at_start: bool) -> &str {
+    let line = raw.rfind('\n').map_or(0, |newline| newline + 1);
+    if (line > 0 || at_start) && raw[line..].chars().all(|c| c == ' ' || c == '\t') {
+        &raw[..line]
+    } else {
+        raw
+    }
+}
+
+// splits off the name at the start of `source`, which runs up to the next whitespace or the end of the block
+fn split_name<'s>(source: &'s str, close: &str) -> (&'s str, &'s str) {
+    let source = source.trim_start();
+    let len = source
+        .find(|c: char| c.is_whitespace())
+        .unwrap_or(source.len());
+    let len = source[..len].find(close).unwrap_or(len);
+    source.split_at(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Operator::*;
+    use super::Token::*;
+    use super::{Syntax, Tokenizer};
+    use std::borrow::Cow;
+
+    #[test]
+    fn tokens() {
+        let source = r#"this is a very {{ adjective | to_upper }} system of extra {{super}}ness.
+            {{2+2/1}}
+            {{ some_var | concat "various tests " }}
+            {{ -3.4 * -count }}"#;
+
+        assert_eq!(
+            Tokenizer::new(source)
+                .collect::<Result<Vec<_>, String>>()
+                .unwrap(),
+            vec![
+                Raw("this is a very "),
+                OpeningBrace,
+                Identifier("adjective"),
+                Op(Pipe),
+                Identifier("to_upper"),
+                ClosingBrace,
+                Raw(" system of extra "),
+                OpeningBrace,
+                Identifier("super"),
+                ClosingBrace,
+                Raw("ness.\n            "),
+                OpeningBrace,
+                Number(2.0),
+                Op(Plus),
+                Number(2.0),
+                Op(Slash),
+                Number(1.0),
+                ClosingBrace,
+                Raw("\n            "),
+                OpeningBrace,
+                Identifier("some_var"),
+                Op(Pipe),
+                Identifier("concat"),
+                StringLiteral("various tests ".into()),
+                ClosingBrace,
+                Raw("\n            "),
+                OpeningBrace,
+                Op(Dash),
+                Number(3.4),
+                Op(Asterisk),
+                Op(Dash),
+                Identifier("count"),
+                ClosingBrace,
+            ]
+        );
+    }
+
+    #[test]
+    fn includes() {
+        assert_eq!(
+            Tokenizer::new("{{> header}}{{>footer}}")
+                .collect::<Result<Vec<_>, String>>()
+                .unwrap(),
+            vec![
+                OpeningBrace,
+                Include("header"),
+                ClosingBrace,
+                OpeningBrace,
+                Include("footer"),
+                ClosingBrace,
+            ]
+        );
+    }
+
+    #[test]
+    fn trim_markers() {
+        let source = "a  \n {{- name -}} \n b {{-3}}{{ x-}}\n\n{{y}} c";
+        assert_eq!(
+            Tokenizer::new(source)
+                .collect::<Result<Vec<_>, String>>()
+                .unwrap(),
+            vec![
+                Raw("a"),
+                OpeningBrace,
+                Identifier("name"),
+                ClosingBrace,
+                Raw("b "),
+                OpeningBrace,
+                Op(Dash),
+                Number(3.0),
+                ClosingBrace,
+                OpeningBrace,
+                Identifier("x"),
+                ClosingBrace,
+                OpeningBrace,
+                Identifier("y"),
+                ClosingBrace,
+                Raw(" c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn trim_blocks() {
+        let syntax = Syntax {
+            trim_blocks: true,
+            ..Syntax::default()
+        };
+        // each statement's newline is trimmed, which leaves the next line's indentation at the start of the raw text
+        let source = "{{#block a}}\n  {{> p}}\n\t{{/block}}\n x";
+        assert_eq!(
+            Tokenizer::with_syntax(source, syntax)
+                .collect::<Result<Vec<_>, String>>()
+                .unwrap(),
+            vec![
+                OpeningBrace,
+                Block("a"),
+                ClosingBrace,
+                OpeningBrace,
+                Include("p"),
+                ClosingBrace,
+                OpeningBrace,
+                EndBlock,
+                ClosingBrace,
+                Raw(" x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let source = "a{{! note }}b{{!-- {{name}}\n --}}c{{!}}{{! unclosed";
+        assert_eq!(
+            Tokenizer::new(source).collect::<Vec<_>>(),
+            vec![
+                Ok(Raw("a")),
+                Ok(Raw("b")),
+                Ok(Raw("c")),
+                Err("Unclosed comment".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn many_skipped_tags() {
+        // skipping a tag moves straight on to the next one, without recursing once per tag
+        let mut source = "{{!x}}".repeat(200_000);
+        source.push_str("{{{{raw}}}}{{{{/raw}}}}".repeat(200_000).as_str());
+        source.push_str("{{=<% %>=}}<%={{ }}=%>".repeat(100_000).as_str());
+        source.push('a');
+        assert_eq!(
+            Tokenizer::new(&source).collect::<Vec<_>>(),
+            vec![Ok(Raw("a"))]
+        );
+    }
+
+    #[test]
+    fn verbatim() {
+        let source =
+            r"a {{{{raw}}}}{{ .Values.name }}{{{{/raw}}}} \{{x}} {{y}}\{{{{{{raw}}}}{{{{/raw}}}}\";
+        assert_eq!(
+            Tokenizer::new(source).collect::<Vec<_>>(),
+            vec![
+                Ok(Raw("a ")),
+                Ok(Raw("{{ .Values.name }}")),
+                Ok(Raw(" ")),
+                Ok(Raw("{{")),
+                Ok(Raw("x}} ")),
+                Ok(OpeningBrace),
+                Ok(Identifier("y")),
+                Ok(ClosingBrace),
+                Ok(Raw("{{")),
+                Ok(Raw("\\")),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::new("{{{{raw}}}} {{").collect::<Vec<_>>(),
+            vec![Err(
+                "No {{{{/raw}}}} to end the {{{{raw}}}} block".to_string()
+            )]
+        );
+
+        // a doubled backslash prints one backslash before the block
+        assert_eq!(
+            Tokenizer::new(r"\\{{name}} a\\{{name}}").collect::<Vec<_>>(),
+            vec![
+                Ok(Raw("\\")),
+                Ok(OpeningBrace),
+                Ok(Identifier("name")),
+                Ok(ClosingBrace),
+                Ok(Raw(" a\\")),
+                Ok(OpeningBrace),
+                Ok(Identifier("name")),
+                Ok(ClosingBrace),
+            ]
+        );
+        // a single backslash always escapes the block, even where it used to be printed
+        assert_eq!(
+            Tokenizer::new(r"C:\{{dir}}").collect::<Vec<_>>(),
+            vec![Ok(Raw("C:")), Ok(Raw("{{")), Ok(Raw("dir}}"))]
+        );
+    }
+
+    #[test]
+    fn delimiters() {
+        let syntax = Syntax::new("[[", "]]").unwrap();
+        let source = "{{a}}[[b -]] [[!c]]\\[[[[[[raw]]]]x]][[[[/raw]]]][[=<% %>=]][[d]]<%e%>";
+        assert_eq!(
+            Tokenizer::with_syntax(source, syntax).collect::<Vec<_>>(),
+            vec![
+                Ok(Raw("{{a}}")),
+                Ok(OpeningBrace),
+                Ok(Identifier("b")),
+                Ok(ClosingBrace),
+                Ok(Raw("[[")),
+                Ok(Raw("x]]")),
+                Ok(Raw("[[d]]")),
+                Ok(OpeningBrace),
+                Ok(Identifier("e")),
+                Ok(ClosingBrace),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::new("{{=<%=}}").collect::<Vec<_>>(),
+            vec![Err(
+                r#"Expected an opening and closing delimiter to switch to, found "<%""#.to_string()
+            )]
+        );
+        assert_eq!(
+            Syntax::new("", "]]"),
+            Err(r#"Invalid delimiter """#.to_string())
+        );
+        assert_eq!(
+            Syntax::new("[[", "] ]"),
+            Err(r#"Invalid delimiter "] ]""#.to_string())
+        );
+    }
+
+    #[test]
+    fn string_literals() {
+        let source = r#"{{ "a }} | b" | x "\"q\"\n\t\\\u{1F600}" "" "é" }}"#;
+        let tokens = Tokenizer::new(source)
+            .collect::<Result<Vec<_>, String>>()
+            .unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                OpeningBrace,
+                StringLiteral("a }} | b".into()),
+                Op(Pipe),
+                Identifier("x"),
+                StringLiteral("\"q\"\n\t\\\u{1F600}".into()),
+                StringLiteral("".into()),
+                StringLiteral("é".into()),
+                ClosingBrace,
+            ]
+        );
+        // only strings with escapes in them are copied
+        let owned = tokens
+            .iter()
+            .filter_map(|token| match *token {
+                StringLiteral(Cow::Owned(_)) => Some(true),
+                StringLiteral(Cow::Borrowed(_)) => Some(false),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(owned, [false, true, false, false]);
+
+        let error = |source| Tokenizer::new(source).nth(1).unwrap().unwrap_err();
+        assert_eq!(
+            error(r#"{{ "a\qb" }}"#),
+            r"Unknown escape sequence \q in string literal"
+        );
+        assert_eq!(
+            error(r#"{{ "\u{d800}" }}"#),
+            r"Invalid unicode escape \u{d800} in string literal"
+        );
+        assert_eq!(
+            error(r#"{{ "\u00e9" }}"#),
+            r"Expected \u{..} with one to six hex digits in string literal"
+        );
+        assert_eq!(error(r#"{{ "abc\" }}"#), "No closing quotation mark");
+    }
+
+    #[test]
+    fn inheritance() {
+        assert_eq!(
+            Tokenizer::new(r#"{{extends "base"}}{{#block body}}hi{{/block}}{{extends}}"#)
+                .collect::<Result<Vec<_>, String>>()
+                .unwrap(),
+            vec![
+                OpeningBrace,
+                Extends("base"),
+                ClosingBrace,
+                OpeningBrace,
+                Block("body"),
+                ClosingBrace,
+                Raw("hi"),
+                OpeningBrace,
+                EndBlock,
+                ClosingBrace,
+                OpeningBrace,
+                Identifier("extends"),
+                ClosingBrace,
+            ]
+        );
+    }
+}